@@ -0,0 +1,19 @@
+use ouroboros::self_referencing;
+
+#[self_referencing]
+struct BoxAndRef {
+    data: Box<i32>,
+    #[borrows(data)]
+    data_ref: &'this i32,
+}
+
+fn main() {
+    let instance = BoxAndRef::new(Box::new(12), |data| data);
+    // The fields cannot be reached under their declared names, under the names they used to be
+    // stored under, or through the storage which holds them, even in the declaring module.
+    let data_ref: &i32 = &instance.data_ref;
+    let mangled_ref: &i32 = &instance.__ouroboros_data_ref;
+    let stored_ref: &i32 = &instance.__ouroboros_fields.0.data_ref;
+    drop(instance);
+    println!("{:?} {:?} {:?}", data_ref, mangled_ref, stored_ref);
+}
@@ -0,0 +1,24 @@
+use ouroboros::self_referencing;
+
+#[self_referencing]
+enum MaybeBoxAndRef {
+    Boxed {
+        data: Box<i32>,
+        #[borrows(data)]
+        data_ref: &'this i32,
+    },
+    Empty,
+}
+
+fn main() {
+    let instance = MaybeBoxAndRef::new_boxed(Box::new(12), |data| data);
+    // The struct generated for a variant keeps its fields just as private as any other
+    // self-referencing struct.
+    if let MaybeBoxAndRef::Boxed(variant) = &instance {
+        let data_ref: &i32 = &variant.data_ref;
+        let mangled_ref: &i32 = &variant.__ouroboros_data_ref;
+        let stored_ref: &i32 = &variant.__ouroboros_fields.0.data_ref;
+        drop(instance);
+        println!("{:?} {:?} {:?}", data_ref, mangled_ref, stored_ref);
+    }
+}
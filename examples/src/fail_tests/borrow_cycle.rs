@@ -0,0 +1,14 @@
+use ouroboros::self_referencing;
+
+#[self_referencing]
+struct S {
+    head: Box<i32>,
+    #[borrows(c, head)]
+    a: Box<&'this i32>,
+    #[borrows(a)]
+    b: Box<&'this i32>,
+    #[borrows(b)]
+    c: Box<&'this i32>,
+}
+
+fn main() { }
@@ -0,0 +1,15 @@
+#![deny(deprecated)]
+
+use ouroboros::self_referencing;
+
+#[self_referencing]
+struct S {
+    data: Box<i32>,
+    text: String,
+    #[borrows(data)]
+    doubled: i32,
+    #[borrows(text)]
+    slice: &'this mut str,
+}
+
+fn main() { }
@@ -0,0 +1,15 @@
+use ouroboros::self_referencing;
+
+trait Consume {
+    fn consume(self) -> usize;
+}
+
+#[self_referencing]
+struct S {
+    data: Vec<i32>,
+    #[borrows(data)]
+    #[ouroboros(delegate(Consume { fn consume(self) -> usize; }))]
+    refs: Vec<&'this i32>,
+}
+
+fn main() { }
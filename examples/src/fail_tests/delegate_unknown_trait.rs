@@ -0,0 +1,11 @@
+use ouroboros::self_referencing;
+
+#[self_referencing]
+struct S {
+    data: Vec<i32>,
+    #[borrows(data)]
+    #[ouroboros(delegate(Iterator, Clone))]
+    iter: std::iter::Copied<std::slice::Iter<'this, i32>>,
+}
+
+fn main() { }
@@ -0,0 +1,15 @@
+use ouroboros::self_referencing;
+
+#[self_referencing]
+struct S {
+    data: Box<i32>,
+    #[borrows(data)]
+    #[ouroboros(pin)]
+    dref: &'this i32,
+}
+
+impl Drop for S {
+    fn drop(&mut self) {}
+}
+
+fn main() { }
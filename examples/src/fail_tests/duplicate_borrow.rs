@@ -0,0 +1,12 @@
+use ouroboros::self_referencing;
+
+#[self_referencing]
+struct S {
+    a: Box<i32>,
+    b: Box<i32>,
+    #[borrows(a)]
+    #[borrows(b, a)]
+    c: (&'this i32, &'this i32),
+}
+
+fn main() { }
@@ -0,0 +1,9 @@
+use ouroboros::self_referencing;
+
+#[self_referencing]
+enum E {
+    A { a: Box<i32> },
+    B,
+}
+
+fn main() { }
@@ -0,0 +1,21 @@
+use ouroboros::self_referencing;
+
+#[self_referencing]
+struct S<'a, 'b> {
+    name: &'a str,
+    data: Box<i32>,
+    #[borrows(data)]
+    pair: (&'this i32, &'b str),
+}
+
+// No bounds are assumed between 'a and 'b, but the struct still cannot outlive either of them.
+fn make<'b>(other: &'b str) -> S<'static, 'b> {
+    S::new("name", Box::new(12), |data| (data, other))
+}
+
+fn main() {
+    let other = String::from("other");
+    let s = make(&other);
+    drop(other);
+    s.with_pair(|pair| println!("{}", pair.1));
+}
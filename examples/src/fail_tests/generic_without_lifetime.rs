@@ -0,0 +1,11 @@
+use ouroboros::self_referencing;
+
+// Without a lifetime parameter, 'this is replaced with 'static, so T has to be 'static.
+#[self_referencing]
+struct S<T> {
+    data: Box<T>,
+    #[borrows(data)]
+    data_ref: &'this T,
+}
+
+fn main() { }
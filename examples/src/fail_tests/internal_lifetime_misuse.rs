@@ -0,0 +1,25 @@
+use ouroboros::self_referencing;
+
+#[self_referencing(lifetime = 'a)]
+struct AlreadyDeclared<'a> {
+    external: &'a str,
+    data: Box<i32>,
+    #[borrows(data)]
+    dref: &'a i32,
+}
+
+#[self_referencing(lifetime = 'buf)]
+struct ThisStillUsed {
+    data: Box<i32>,
+    #[borrows(data)]
+    dref: &'this i32,
+}
+
+#[self_referencing(lifetime = 'static)]
+struct StaticLifetime {
+    data: Box<i32>,
+    #[borrows(data)]
+    dref: &'static i32,
+}
+
+fn main() { }
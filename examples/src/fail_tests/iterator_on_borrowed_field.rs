@@ -0,0 +1,11 @@
+use ouroboros::self_referencing;
+
+#[self_referencing]
+struct S {
+    #[ouroboros(iterator)]
+    data: Vec<i32>,
+    #[borrows(data)]
+    iter: std::slice::Iter<'this, i32>,
+}
+
+fn main() { }
@@ -0,0 +1,18 @@
+use ouroboros::self_referencing;
+
+#[self_referencing(no_doc, chain_hak)]
+struct S {
+    a: Box<i32>,
+    #[borrows(a, c)]
+    b: &'this i32,
+    #[borrows(mut a)]
+    #[ouroboros(iterator, sorted)]
+    d: &'this mut i32,
+}
+
+// The struct still exists, so using it only reports the errors above.
+fn takes_s(s: &S) -> &i32 {
+    &s.a
+}
+
+fn main() { }
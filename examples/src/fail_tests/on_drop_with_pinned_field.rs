@@ -0,0 +1,15 @@
+use ouroboros::self_referencing;
+use std::future::Future;
+use std::pin::Pin;
+
+fn cleanup<T>(_fields: T) {}
+
+#[self_referencing(on_drop = cleanup)]
+struct S {
+    a: Box<i32>,
+    #[borrows(a)]
+    #[ouroboros(future)]
+    b: Pin<Box<dyn Future<Output = i32> + 'this>>,
+}
+
+fn main() { }
@@ -1,10 +1,10 @@
 use ouroboros::self_referencing;
 
-#[self_referencing]
+#[self_referencing(no_doc = "yes")]
 struct S {
+    a: Box<i32>,
     #[borrows(a)]
     b: &'this i32,
-    a: Box<i32>,
 }
 
 fn main() { }
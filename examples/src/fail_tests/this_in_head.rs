@@ -0,0 +1,9 @@
+use ouroboros::self_referencing;
+
+#[self_referencing]
+struct S {
+    data: Box<i32>,
+    dref: &'this i32,
+}
+
+fn main() { }
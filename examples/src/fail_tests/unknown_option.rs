@@ -0,0 +1,10 @@
+use ouroboros::self_referencing;
+
+#[self_referencing(no_doc, chain_hak)]
+struct S {
+    a: Box<i32>,
+    #[borrows(a)]
+    b: &'this i32,
+}
+
+fn main() { }
@@ -12,10 +12,11 @@ pub struct BoxAndRef {
 }
 
 #[self_referencing(chain_hack)]
-#[allow(clippy::redundant_allocation)]
+#[allow(deprecated, clippy::redundant_allocation)]
 /// A chain of references, where c references b which references a. This is an example of a struct
-/// which requires using [chain_hack](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#using-chain_hack)
-/// as of the time this was written.
+/// which required using [chain_hack](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#chained-borrows-and-chain_hack)
+/// at the time it was written. The option is now deprecated and has no effect, see
+/// [ChainedReferences].
 pub struct ChainHack {
     a: Box<i32>,
     #[borrows(a)]
@@ -24,6 +25,17 @@ pub struct ChainHack {
     c: Box<&'this i32>,
 }
 
+#[self_referencing]
+#[allow(clippy::redundant_allocation)]
+/// The same chain of references as [ChainHack], which no longer needs any options.
+pub struct ChainedReferences {
+    a: Box<i32>,
+    #[borrows(a)]
+    b: Box<&'this i32>,
+    #[borrows(b)]
+    c: Box<&'this i32>,
+}
+
 #[self_referencing]
 /// The example provided in the documentation.
 pub struct DocumentationExample {
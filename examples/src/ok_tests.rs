@@ -1,4 +1,9 @@
 use ouroboros::self_referencing;
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::sync::Arc;
 
 // All tests here should compile and run correctly and pass Miri's safety checks.
 
@@ -17,6 +22,7 @@ struct BoxAndMutRef {
 }
 
 #[self_referencing(chain_hack, no_doc)]
+#[allow(deprecated, clippy::redundant_allocation)]
 struct ChainedAndUndocumented {
     data: Box<i32>,
     #[borrows(data)]
@@ -25,6 +31,541 @@ struct ChainedAndUndocumented {
     ref2: &'this &'this i32
 }
 
+type MyBox<T> = Box<T>;
+
+#[self_referencing]
+#[allow(clippy::redundant_allocation)]
+struct ChainedThroughPaths {
+    data: MyBox<i32>,
+    #[borrows(data)]
+    ref1: std::boxed::Box<&'this i32>,
+    #[borrows(ref1)]
+    ref2: &'this i32,
+}
+
+struct Parsed<'a> {
+    words: Vec<&'a str>,
+}
+
+// Chains where the intermediate field is a StableDeref container other than Box.
+#[self_referencing]
+struct VecChain {
+    text: String,
+    #[borrows(text)]
+    words: Vec<&'this str>,
+    #[borrows(words)]
+    last_two: &'this [&'this str],
+}
+
+#[self_referencing]
+struct StringChain {
+    source: Box<str>,
+    #[borrows(source)]
+    upper: String,
+    #[borrows(upper)]
+    first_word: &'this str,
+}
+
+#[self_referencing]
+struct RcChain {
+    text: String,
+    #[borrows(text)]
+    parsed: Rc<Parsed<'this>>,
+    #[borrows(parsed)]
+    first_word: &'this str,
+}
+
+#[self_referencing]
+struct ArcChain {
+    text: Arc<str>,
+    #[borrows(text)]
+    parsed: Arc<Parsed<'this>>,
+    #[borrows(parsed)]
+    words: &'this [&'this str],
+}
+
+#[self_referencing]
+struct BoxedSliceChain {
+    data: Box<[i32]>,
+    #[borrows(mut data)]
+    evens: Box<[&'this mut i32]>,
+    #[borrows(mut evens)]
+    first_even: &'this mut &'this mut i32,
+}
+
+// Fields borrowed as a whole rather than through their contents.
+#[self_referencing]
+struct ContainerRef {
+    data: Vec<i32>,
+    #[borrows(&data)]
+    data_ref: &'this Vec<i32>,
+}
+
+#[self_referencing]
+struct ContainerMutRef {
+    data: Vec<i32>,
+    #[borrows(&mut data)]
+    data_ref: &'this mut Vec<i32>,
+}
+
+#[self_referencing]
+struct ContainerAndContents {
+    data: std::collections::HashMap<i32, String>,
+    names: Vec<String>,
+    #[borrows(&data, names, &names)]
+    lookup: (&'this std::collections::HashMap<i32, String>, &'this [String], usize),
+}
+
+#[self_referencing]
+#[allow(clippy::borrowed_box)]
+struct TraitObjectRef {
+    data: Box<dyn Fn() -> i32>,
+    #[borrows(&data)]
+    data_ref: &'this Box<dyn Fn() -> i32>,
+}
+
+// Heads which may or may not be present.
+#[self_referencing]
+struct OptionalHead {
+    buffer: Option<Box<str>>,
+    #[borrows(buffer)]
+    parsed: Option<&'this str>,
+}
+
+#[self_referencing]
+struct OptionalMutHead {
+    data: std::option::Option<Vec<i32>>,
+    #[borrows(mut data)]
+    first: Option<&'this mut i32>,
+}
+
+// One head is shared while the other is mutably borrowed by the same field.
+#[self_referencing]
+struct MixedBorrows {
+    source: Box<[i32]>,
+    target: Vec<i32>,
+    #[borrows(source, mut target)]
+    pair: (&'this [i32], &'this mut [i32]),
+}
+
+// The tail is declared first, before the fields it borrows.
+#[self_referencing]
+struct TailFirst {
+    #[borrows(words, text)]
+    summary: (usize, &'this str),
+    #[borrows(text)]
+    words: Vec<&'this str>,
+    text: String,
+}
+
+// Records the text it borrows when dropped, which is only valid while the text still exists.
+struct RecordOnDrop<'a> {
+    text: &'a str,
+    log: Rc<RefCell<Vec<String>>>,
+}
+
+impl Drop for RecordOnDrop<'_> {
+    fn drop(&mut self) {
+        self.log.borrow_mut().push(self.text.to_owned());
+    }
+}
+
+// Fields are kept in declaration order, even though recorder has to be dropped before text.
+#[self_referencing]
+#[repr(C)]
+struct ReprC {
+    count: u32,
+    #[borrows(text)]
+    recorder: RecordOnDrop<'this>,
+    text: String,
+}
+
+// Saves the words to the log right before they are dropped.
+fn save_words(fields: WordLogBorrowedMutFields<'_, '_>) {
+    let words = fields.words.iter().map(|word| word.to_string());
+    fields.log.borrow_mut().extend(words);
+}
+
+#[self_referencing(on_drop = save_words)]
+struct WordLog {
+    log: Rc<RefCell<Vec<String>>>,
+    text: String,
+    #[borrows(text)]
+    words: Vec<&'this str>,
+}
+
+// The same borrows as MixedBorrows, split across several attributes.
+#[self_referencing]
+struct SplitBorrows {
+    source: Box<[i32]>,
+    target: Vec<i32>,
+    #[borrows(source)]
+    #[borrows(mut target)]
+    pair: (&'this [i32], &'this mut [i32]),
+}
+
+#[self_referencing]
+struct Layered {
+    config: String,
+    buffer: Vec<u8>,
+    #[borrows(config)]
+    name: &'this str,
+    #[borrows(config, mut buffer)]
+    view: (&'this str, &'this mut [u8]),
+}
+
+// Generates SharedCacheMutex, which can be shared between threads.
+#[self_referencing(sync)]
+struct SharedCache {
+    text: String,
+    #[borrows(text)]
+    words: Vec<&'this str>,
+    hits: u32,
+}
+
+// Iterating over this struct iterates over the characters of its text.
+#[self_referencing]
+struct OwnedChars {
+    text: String,
+    #[borrows(text)]
+    #[ouroboros(iterator)]
+    chars: std::str::Chars<'this>,
+}
+
+#[self_referencing]
+struct DelegatedIterator {
+    data: Vec<i32>,
+    #[borrows(data)]
+    #[ouroboros(delegate(Iterator, DoubleEndedIterator, std::iter::ExactSizeIterator))]
+    values: std::iter::Copied<std::slice::Iter<'this, i32>>,
+}
+
+#[self_referencing]
+struct DelegatedIo {
+    input: Vec<u8>,
+    output: Vec<u8>,
+    #[borrows(input)]
+    #[ouroboros(delegate(std::io::Read, io::BufRead))]
+    reader: std::io::Cursor<&'this [u8]>,
+    #[borrows(&mut output)]
+    #[ouroboros(delegate(std::io::Write))]
+    writer: &'this mut Vec<u8>,
+}
+
+#[self_referencing]
+struct DelegatedFormatting {
+    text: String,
+    #[borrows(text)]
+    #[ouroboros(delegate(std::fmt::Display, fmt::Debug))]
+    trimmed: &'this str,
+}
+
+trait Tally<T> {
+    fn total(&self) -> T;
+    fn forget_first(&mut self, count: usize);
+}
+
+impl Tally<i32> for Vec<&i32> {
+    fn total(&self) -> i32 {
+        self.iter().copied().sum()
+    }
+
+    fn forget_first(&mut self, count: usize) {
+        self.drain(..count);
+    }
+}
+
+#[self_referencing]
+struct DelegatedListedTrait {
+    data: Vec<i32>,
+    #[borrows(data)]
+    #[ouroboros(delegate(Tally<i32> {
+        fn total(&self) -> i32;
+        fn forget_first(&mut self, _: usize);
+    }))]
+    refs: Vec<&'this i32>,
+}
+
+// A future which is polled a few times and is never Unpin.
+struct SumSlowly<'a> {
+    data: &'a [i32],
+    index: usize,
+    sum: i32,
+    _pinned: std::marker::PhantomPinned,
+}
+
+impl std::future::Future for SumSlowly<'_> {
+    type Output = i32;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<i32> {
+        // Safety: No fields are moved out of.
+        let this = unsafe { self.get_unchecked_mut() };
+        if let Some(value) = this.data.get(this.index) {
+            this.index += 1;
+            this.sum += value;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        } else {
+            Poll::Ready(this.sum)
+        }
+    }
+}
+
+#[self_referencing]
+struct PinnedFuture {
+    data: Vec<i32>,
+    #[borrows(data)]
+    #[ouroboros(future)]
+    sum: SumSlowly<'this>,
+}
+
+#[self_referencing]
+struct BoxedFuture {
+    body: String,
+    #[borrows(body)]
+    #[ouroboros(future)]
+    length: Pin<Box<dyn std::future::Future<Output = usize> + 'this>>,
+}
+
+struct Words<'a>(std::str::SplitWhitespace<'a>);
+
+impl futures_core::Stream for Words<'_> {
+    type Item = String;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<String>> {
+        Poll::Ready(self.0.next().map(str::to_owned))
+    }
+}
+
+#[self_referencing]
+struct WordStream {
+    text: String,
+    #[borrows(text)]
+    #[ouroboros(stream)]
+    words: Words<'this>,
+}
+
+// Each variant which borrows from its own fields becomes a self-referencing struct.
+#[self_referencing]
+enum DecoderState {
+    Idle,
+    Header {
+        buffer: Box<[u8]>,
+        #[borrows(buffer)]
+        magic: &'this [u8],
+    },
+    Body {
+        text: String,
+        #[borrows(mut text)]
+        cursor: &'this mut str,
+    },
+    Done(u32),
+}
+
+#[self_referencing]
+enum GenericEnum<T: 'static> {
+    Value {
+        data: Box<T>,
+        #[borrows(data)]
+        dref: &'this T,
+    },
+    Empty,
+}
+
+// Variants only take the generic parameters they use, so `Count` does not need `T`.
+#[self_referencing]
+enum PartlyGenericEnum<'a, T: 'a> {
+    Value {
+        data: Box<T>,
+        #[borrows(data)]
+        dref: &'this T,
+    },
+    Count {
+        items: Vec<u8>,
+        #[borrows(items)]
+        first: Option<&'this u8>,
+    },
+    Named {
+        name: &'a str,
+    },
+}
+
+#[self_referencing(lifetime = 'buf)]
+enum CustomLifetimeEnum {
+    Words {
+        buffer: String,
+        #[borrows(buffer)]
+        words: Vec<&'buf str>,
+    },
+    Empty,
+}
+
+// Debug is implemented through the accessors rather than derived for the stored fields. Mutably
+// borrowed fields cannot be accessed, so they are left out.
+#[self_referencing]
+#[derive(Debug)]
+struct DerivedDebug<T: 'static> {
+    text: String,
+    #[borrows(text)]
+    words: Vec<&'this str>,
+    counts: Vec<i32>,
+    #[borrows(mut counts)]
+    first_count: Option<&'this mut i32>,
+    value: T,
+}
+
+#[self_referencing]
+#[derive(Debug)]
+enum DerivedDebugEnum {
+    Words {
+        text: String,
+        #[borrows(text)]
+        words: Vec<&'this str>,
+    },
+    Empty,
+}
+
+// Heads only need to outlive the first lifetime parameter of the struct, which replaces 'this
+// internally.
+#[self_referencing]
+struct GenericHead<'a, T: 'a> {
+    items: Vec<T>,
+    #[borrows(items)]
+    pairs: Vec<(&'this T, &'this T)>,
+}
+
+#[self_referencing]
+struct BorrowedSliceHead<'a> {
+    bytes: &'a [u8],
+    #[borrows(bytes)]
+    first_half: &'this [u8],
+}
+
+#[self_referencing]
+struct GenericBoxHead<'a, T: ?Sized + 'a> {
+    data: Box<T>,
+    #[borrows(mut data)]
+    dref: &'this mut T,
+}
+
+struct Config {
+    separator: char,
+}
+
+struct Tokens<'t, 'c> {
+    config: &'c Config,
+    words: Vec<&'t str>,
+}
+
+// 'this is shorter than every lifetime of the struct, so self-referencing fields can mention 'a.
+#[self_referencing]
+struct Ctx<'a> {
+    config: &'a Config,
+    buffer: Box<str>,
+    #[borrows(buffer)]
+    tokens: Tokens<'this, 'a>,
+}
+
+// Without bounds between the lifetimes, 'this is replaced with 'a, and `first_config` makes Rust
+// infer that 'b outlives 'a.
+#[self_referencing]
+struct TwoLifetimes<'a, 'b> {
+    name: &'a str,
+    configs: Vec<&'b Config>,
+    #[borrows(configs)]
+    first_config: &'this &'b Config,
+    #[borrows(configs)]
+    tokens: Tokens<'this, 'b>,
+}
+
+fn make_two_lifetimes<'a, 'b>(name: &'a str, config: &'b Config) -> TwoLifetimes<'a, 'b> {
+    TwoLifetimesBuilder {
+        name,
+        configs: vec![config],
+        first_config_builder: |configs| &configs[0],
+        tokens_builder: |configs| Tokens { config: configs[0], words: vec!["word"] },
+    }
+    .build()
+}
+
+// 'this is replaced with the lifetime the bounds declare to be the shortest, wherever it is
+// declared, so 'short can be much shorter than 'long.
+#[self_referencing]
+struct ShortestLifetimeLast<'long: 'short, 'short> {
+    long: &'long str,
+    short: &'short str,
+    #[borrows(&long, short)]
+    both: (&'this &'long str, &'this str),
+}
+
+// Only the 'this lifetime is replaced internally, other things spelled `this` are left alone.
+mod this {
+    pub struct Wrapper<'a>(pub &'a str);
+}
+
+macro_rules! boxed {
+    ($typ:ty) => {
+        Box<$typ>
+    };
+}
+
+#[self_referencing]
+struct ThisInPaths {
+    data: boxed!(str),
+    #[borrows(data)]
+    wrapper: this::Wrapper<'this>,
+}
+
+#[self_referencing(lifetime = 'buf)]
+struct CustomLifetime {
+    buffer: String,
+    #[borrows(buffer)]
+    words: Vec<&'buf str>,
+    #[borrows(words)]
+    first: Option<&'buf &'buf str>,
+}
+
+#[allow(deprecated)]
+mod chain_hack_compat {
+    use ouroboros::self_referencing;
+
+    // chain_hack is deprecated but must still be accepted.
+    #[self_referencing(chain_hack)]
+    pub struct ChainHack {
+        pub data: Box<i32>,
+        #[borrows(data)]
+        pub dref: &'this i32,
+    }
+}
+
+// The generated items are placed next to the struct, so restricted visibilities such as
+// pub(super) refer to the module the struct was declared in.
+mod nested {
+    use ouroboros::self_referencing;
+
+    #[self_referencing]
+    pub(super) struct Nested {
+        data: Vec<i32>,
+        #[borrows(data)]
+        last: Option<&'this i32>,
+    }
+}
+
+// Both names are `http_cache` in snake case, which must not cause any conflicts.
+#[self_referencing]
+struct HTTPCache {
+    data: String,
+    #[borrows(data)]
+    view: &'this str,
+}
+
+#[self_referencing]
+struct HttpCache {
+    data: String,
+    #[borrows(data)]
+    view: &'this str,
+}
+
 #[test]
 fn box_and_ref() {
     let bar = BoxAndRefBuilder {
@@ -86,6 +627,840 @@ fn into_heads() {
     assert!(bar.into_heads().data == Box::new(12));
 }
 
+#[test]
+fn chained_through_paths() {
+    let chain = ChainedThroughPathsBuilder {
+        data: Box::new(12),
+        ref1_builder: |data| Box::new(data),
+        ref2_builder: |ref1| *ref1,
+    }
+    .build();
+    assert_eq!(chain.with_ref2(|ref2| **ref2), 12);
+    assert_eq!(chain.with_ref1_contents(|ref1| **ref1), 12);
+}
+
+#[test]
+fn vec_chain() {
+    let chain = VecChainBuilder {
+        text: "a b c".to_owned(),
+        words_builder: |text| text.split(' ').collect(),
+        last_two_builder: |words| &words[1..],
+    }
+    .build();
+    assert_eq!(chain.with_last_two(|last_two| last_two.to_vec()), vec!["b", "c"]);
+    assert_eq!(chain.with_words_contents(|words| words.len()), 3);
+}
+
+#[test]
+fn string_chain() {
+    let chain = StringChainBuilder {
+        source: "hello world".into(),
+        upper_builder: |source| source.to_uppercase(),
+        first_word_builder: |upper| upper.split(' ').next().unwrap(),
+    }
+    .build();
+    assert_eq!(chain.with_first_word(|word| *word), "HELLO");
+}
+
+#[test]
+fn rc_chain() {
+    let chain = RcChainBuilder {
+        text: "first second".to_owned(),
+        parsed_builder: |text| Rc::new(Parsed { words: text.split(' ').collect() }),
+        first_word_builder: |parsed| parsed.words[0],
+    }
+    .build();
+    assert_eq!(chain.with_first_word(|word| *word), "first");
+    assert_eq!(chain.with_parsed_contents(|parsed| parsed.words[1]), "second");
+}
+
+#[test]
+fn with_borrowed_head() {
+    let chain = VecChainBuilder {
+        text: "a b c".to_owned(),
+        words_builder: |text| {
+            let mut words = Vec::with_capacity(8);
+            words.extend(text.split(' '));
+            words
+        },
+        last_two_builder: |words| &words[1..],
+    }
+    .build();
+    assert_eq!(chain.with_text(|text| text.len()), 5);
+    assert!(chain.with_words(|words| words.capacity()) >= 8);
+    chain.with(|fields| {
+        assert_eq!(fields.words.len(), 3);
+        assert_eq!(fields.words_contents, &["a", "b", "c"]);
+    });
+    let chain = RcChainBuilder {
+        text: "first second".to_owned(),
+        parsed_builder: |text| Rc::new(Parsed { words: text.split(' ').collect() }),
+        first_word_builder: |parsed| parsed.words[0],
+    }
+    .build();
+    assert_eq!(chain.with(|fields| Rc::strong_count(fields.parsed)), 1);
+    assert_eq!(chain.with_parsed(|parsed| parsed.words.len()), 2);
+}
+
+#[test]
+fn replace_head() {
+    let mut chain = VecChainBuilder {
+        text: "a b c".to_owned(),
+        words_builder: |text| text.split(' ').collect(),
+        last_two_builder: |words| &words[1..],
+    }
+    .build();
+    let old = chain.replace_text(
+        "w x y z".to_owned(),
+        |text| text.split(' ').collect(),
+        |words| &words[2..],
+    );
+    assert_eq!(old, "a b c");
+    assert_eq!(chain.with_words_contents(|words| words.len()), 4);
+    assert_eq!(chain.with_last_two(|last_two| last_two.to_vec()), vec!["y", "z"]);
+}
+
+#[test]
+fn replace_head_keeps_unaffected_fields() {
+    let mut layered = Layered::new(
+        "config".to_owned(),
+        vec![1, 2, 3],
+        |config| &config[..3],
+        |config, buffer| (config, buffer),
+    );
+    layered.with_view_mut(|view| view.1[0] = 10);
+    let old = layered.replace_buffer(vec![4, 5], |config, buffer| {
+        buffer[1] = 50;
+        (&config[3..], buffer)
+    });
+    assert_eq!(old, vec![10, 2, 3]);
+    assert_eq!(layered.with_name(|name| *name), "con");
+    layered.with_view(|view| {
+        assert_eq!(view.0, "fig");
+        assert_eq!(view.1, [4, 50]);
+    });
+    let old = layered.replace_config(
+        "other".to_owned(),
+        |config| config,
+        |config, buffer| (&config[..1], buffer),
+    );
+    assert_eq!(old, "config");
+    assert_eq!(layered.with_name(|name| *name), "other");
+    assert_eq!(layered.with_view(|view| view.0), "o");
+    assert_eq!(layered.into_heads().buffer, vec![4, 50]);
+}
+
+#[test]
+fn generic_non_static_head() {
+    let text = "one two three".to_owned();
+    let words: Vec<&str> = text.split(' ').collect();
+    let generic = GenericHeadBuilder {
+        items: words,
+        pairs_builder: |items| items.windows(2).map(|pair| (&pair[0], &pair[1])).collect(),
+    }
+    .build();
+    assert_eq!(generic.with_pairs(|pairs| pairs.len()), 2);
+    assert_eq!(generic.with_pairs(|pairs| *pairs[1].1), "three");
+    let words = generic.into_heads().items;
+    assert_eq!(words, ["one", "two", "three"]);
+    let empty = GenericHeadTryBuilder {
+        items: words,
+        pairs_builder: |_| Result::<_, ()>::Ok(Vec::new()),
+    }
+    .try_build()
+    .unwrap();
+    assert_eq!(empty.with_pairs(|pairs| pairs.len()), 0);
+}
+
+#[test]
+fn lifetime_parameterized_head() {
+    let bytes = vec![1, 2, 3, 4];
+    let head = BorrowedSliceHead::new(&bytes, |bytes| &bytes[..bytes.len() / 2]);
+    assert_eq!(head.with_first_half(|half| half.to_vec()), [1, 2]);
+    assert_eq!(head.with_bytes(|bytes| bytes.len()), 4);
+
+    let mut counter = 0;
+    let mut boxed: GenericBoxHead<dyn FnMut() -> i32> = GenericBoxHead::new(
+        Box::new(|| {
+            counter += 1;
+            counter
+        }),
+        |data| data,
+    );
+    boxed.with_dref_mut(|dref| dref());
+    assert_eq!(boxed.with_dref_mut(|dref| dref()), 2);
+    drop(boxed);
+    assert_eq!(counter, 2);
+}
+
+#[test]
+fn external_lifetime() {
+    let config = Config { separator: ',' };
+    let ctx = Ctx::new(&config, "a,b,c".into(), |buffer| Tokens {
+        config: &config,
+        words: buffer.split(config.separator).collect(),
+    });
+    assert_eq!(ctx.with_tokens(|tokens| tokens.words.len()), 3);
+    assert_eq!(ctx.with_tokens(|tokens| tokens.config.separator), ',');
+    assert_eq!(ctx.with_config(|config| config.separator), ',');
+}
+
+#[test]
+fn multiple_external_lifetimes() {
+    let config = Config { separator: ' ' };
+    let name = "name".to_owned();
+    let two = make_two_lifetimes(&name, &config);
+    assert_eq!(two.with_first_config(|config| config.separator), ' ');
+    assert_eq!(two.with_tokens(|tokens| tokens.words[0]), "word");
+    assert_eq!(two.with_tokens(|tokens| tokens.config.separator), ' ');
+    let heads = two.into_heads();
+    assert_eq!(heads.name, "name");
+}
+
+#[test]
+fn this_in_paths() {
+    let value = ThisInPaths::new("text".into(), |data| this::Wrapper(data));
+    assert_eq!(value.with_wrapper(|wrapper| wrapper.0), "text");
+}
+
+#[test]
+fn custom_lifetime_name() {
+    let value = CustomLifetimeBuilder {
+        buffer: "one two".to_owned(),
+        words_builder: |buffer| buffer.split(' ').collect(),
+        first_builder: |words| words.first(),
+    }
+    .build();
+    assert_eq!(value.with_first(|first| first.copied()), Some("one"));
+    assert_eq!(value.with_words_contents(|words| words.len()), 2);
+}
+
+#[test]
+fn arc_chain() {
+    let chain = ArcChainBuilder {
+        text: "x y".into(),
+        parsed_builder: |text| Arc::new(Parsed { words: text.split(' ').collect() }),
+        words_builder: |parsed| &parsed.words[..],
+    }
+    .build();
+    assert_eq!(chain.with_words(|words| words.to_vec()), vec!["x", "y"]);
+}
+
+#[test]
+fn boxed_slice_chain() {
+    let mut chain = BoxedSliceChainBuilder {
+        data: vec![1, 2, 3, 4].into_boxed_slice(),
+        evens_builder: |data| data.iter_mut().filter(|value| **value % 2 == 0).collect(),
+        first_even_builder: |evens| &mut evens[0],
+    }
+    .build();
+    assert_eq!(chain.with_first_even(|first| ***first), 2);
+    chain.with_first_even_mut(|first| ***first = 20);
+    assert_eq!(chain.into_heads().data[..], [1, 20, 3, 4]);
+}
+
+#[test]
+fn container_ref() {
+    let value = ContainerRefBuilder {
+        data: Vec::with_capacity(16),
+        data_ref_builder: |data| data,
+    }
+    .build();
+    assert!(value.with_data_ref(|data_ref| data_ref.capacity()) >= 16);
+    assert!(value.with_data(|data| data.is_empty()));
+    // Moving the struct must not invalidate the reference to the container.
+    let moved = Box::new(value);
+    assert!(moved.with_data_ref(|data_ref| data_ref.capacity()) >= 16);
+}
+
+// The box of data is freed while into_heads still holds the reference to it, which must not be
+// considered a use of that reference.
+#[test]
+fn container_into_heads() {
+    let value = ContainerRefBuilder {
+        data: vec![1, 2, 3],
+        data_ref_builder: |data| data,
+    }
+    .build();
+    assert_eq!(value.into_heads().data, vec![1, 2, 3]);
+}
+
+#[test]
+fn container_mut_ref() {
+    let mut value = ContainerMutRefBuilder {
+        data: vec![1],
+        data_ref_builder: |data| data,
+    }
+    .build();
+    value.with_data_ref_mut(|data_ref| data_ref.extend(2..=100));
+    assert_eq!(value.with_data_ref(|data_ref| data_ref.len()), 100);
+    let moved = Box::new(value);
+    assert_eq!(moved.with_data_ref(|data_ref| data_ref[99]), 100);
+}
+
+#[test]
+fn container_and_contents() {
+    let mut data = std::collections::HashMap::new();
+    data.insert(1, "one".to_owned());
+    let value = ContainerAndContentsBuilder {
+        data,
+        names: vec!["a".to_owned(), "b".to_owned()],
+        lookup_builder: |data, names, names_vec| (data, names, names_vec.capacity()),
+    }
+    .build();
+    value.with_lookup(|(data, names, capacity)| {
+        assert_eq!(data[&1], "one");
+        assert_eq!(names.len(), 2);
+        assert!(*capacity >= 2);
+    });
+    value.with(|fields| {
+        assert_eq!(fields.names_contents.len(), 2);
+        assert_eq!(fields.names.len(), 2);
+        assert_eq!(fields.data.len(), 1);
+    });
+}
+
+#[test]
+fn trait_object_ref() {
+    let value = TraitObjectRefBuilder {
+        data: Box::new(|| 12),
+        data_ref_builder: |data| data,
+    }
+    .build();
+    assert_eq!(value.with_data_ref(|data_ref| data_ref()), 12);
+}
+
+#[test]
+fn optional_head() {
+    let some = OptionalHeadBuilder {
+        buffer: Some("contents".into()),
+        parsed_builder: |buffer| buffer.map(|buffer| &buffer[..4]),
+    }
+    .build();
+    assert_eq!(some.with_parsed(|parsed| *parsed), Some("cont"));
+    assert_eq!(some.with_buffer_contents(|buffer| buffer), Some("contents"));
+    assert!(some.with(|fields| fields.buffer_contents.is_some()));
+    let none = OptionalHeadBuilder {
+        buffer: None,
+        parsed_builder: |buffer| buffer,
+    }
+    .build();
+    assert_eq!(none.with_parsed(|parsed| *parsed), None);
+    assert_eq!(none.with_buffer_contents(|buffer| buffer), None);
+    assert_eq!(none.into_heads().buffer, None);
+}
+
+#[test]
+fn optional_mut_head() {
+    let mut value = OptionalMutHeadBuilder {
+        data: Some(vec![1, 2]),
+        first_builder: |data| data.and_then(|data| data.first_mut()),
+    }
+    .build();
+    value.with_first_mut(|first| **first.as_mut().unwrap() = 10);
+    assert_eq!(value.with_first(|first| first.as_deref().copied()), Some(10));
+}
+
+#[test]
+fn chain_hack_is_no_op() {
+    let value = chain_hack_compat::ChainHackBuilder {
+        data: Box::new(12),
+        dref_builder: |data| data,
+    }
+    .build();
+    assert_eq!(value.with_dref(|dref| **dref), 12);
+}
+
+#[test]
+fn chained_and_undocumented() {
+    static OTHER: &i32 = &34;
+    let value = ChainedAndUndocumentedBuilder {
+        data: Box::new(12),
+        ref1_builder: |data| Box::new(data),
+        ref2_builder: |_| &OTHER,
+    }
+    .build();
+    assert_eq!(value.with_ref1(|ref1| ***ref1), 12);
+    assert_eq!(value.with_ref2(|ref2| ***ref2), 34);
+}
+
+#[test]
+fn shortest_lifetime_last() {
+    let short = String::from("short");
+    let value: ShortestLifetimeLast<'static, '_> = ShortestLifetimeLastBuilder {
+        long: "long",
+        short: &short,
+        both_builder: |long, short| (long, short),
+    }
+    .build();
+    assert_eq!(value.with_both(|both| (*both.0, both.1)), ("long", "short"));
+    value.with(|fields| assert_eq!(*fields.long, "long"));
+}
+
+#[test]
+fn derived_debug() {
+    let value = DerivedDebug::new(
+        "a b".to_owned(),
+        |text| text.split(' ').collect(),
+        vec![1],
+        |counts| counts.first_mut(),
+        5,
+    );
+    assert_eq!(
+        format!("{:?}", value),
+        r#"DerivedDebug { text: "a b", words: ["a", "b"], first_count: Some(1), value: 5, .. }"#
+    );
+    let words = DerivedDebugEnum::new_words("a b".to_owned(), |text| text.split(' ').collect());
+    assert_eq!(
+        format!("{:?}", words),
+        r#"Words(DerivedDebugEnumWords { text: "a b", words: ["a", "b"] })"#
+    );
+    assert_eq!(format!("{:?}", DerivedDebugEnum::Empty), "Empty");
+}
+
+#[test]
+fn with_mut_provides_shared_contents() {
+    let mut value = MixedBorrowsBuilder {
+        source: vec![1, 2, 3].into_boxed_slice(),
+        target: vec![0; 3],
+        pair_builder: |source, target| (source, target),
+    }
+    .build();
+    value.with_mut(|fields| {
+        let source_contents: &[i32] = fields.source_contents;
+        for (target, source) in fields.pair.1.iter_mut().zip(source_contents) {
+            *target = source * 2;
+        }
+        assert_eq!(fields.pair.0, source_contents);
+    });
+    assert_eq!(value.with_pair(|pair| pair.1.to_vec()), vec![2, 4, 6]);
+}
+
+#[test]
+fn fields_in_any_order() {
+    let mut value = TailFirst::new(
+        |words, text| (words.len(), text),
+        |text| text.split(' ').collect(),
+        "one two three".to_owned(),
+    );
+    assert_eq!(value.with_summary(|summary| *summary), (3, "one two three"));
+    let old_text = value.replace_text(
+        "four five".to_owned(),
+        |words, text| (words.len(), text),
+        |text| text.split(' ').collect(),
+    );
+    assert_eq!(old_text, "one two three");
+    assert_eq!(value.with_words(|words| words.clone()), vec!["four", "five"]);
+    assert_eq!(value.with_summary(|summary| summary.0), 2);
+    assert_eq!(value.into_heads().text, "four five");
+}
+
+#[test]
+fn repr_c_keeps_declaration_order() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut value = ReprC::new(
+        7,
+        |text| RecordOnDrop {
+            text,
+            log: log.clone(),
+        },
+        "still alive".to_owned(),
+    );
+    assert_eq!(value.count_ptr() as usize, &value as *const ReprC as usize);
+    assert!((value.recorder_ptr() as usize) < (value.text_ptr() as usize));
+    unsafe { *value.count_mut_ptr() += 1 };
+    assert_eq!(value.with_count(|count| *count), 8);
+    drop(value);
+    assert_eq!(*log.borrow(), vec!["still alive".to_owned()]);
+}
+
+#[test]
+fn on_drop_hook() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut value = WordLog::new(log.clone(), "a b".to_owned(), |text| {
+        text.split(' ').collect()
+    });
+    value.with_words_mut(|words| words.pop());
+    drop(value);
+    assert_eq!(*log.borrow(), vec!["a".to_owned()]);
+    let value = WordLog::new(log.clone(), "c".to_owned(), |text| vec![text]);
+    let heads = value.into_heads();
+    assert_eq!(heads.text, "c");
+    assert_eq!(*log.borrow(), vec!["a".to_owned(), "c".to_owned()]);
+}
+
+#[test]
+fn split_borrows() {
+    let mut value = SplitBorrows::new(
+        vec![1, 2, 3].into_boxed_slice(),
+        vec![0; 3],
+        |source, target| (source, target),
+    );
+    value.with_pair_mut(|pair| pair.1.copy_from_slice(pair.0));
+    assert_eq!(value.with_pair(|pair| pair.1.to_vec()), vec![1, 2, 3]);
+}
+
+#[test]
+fn sync_wrapper_across_threads() {
+    let cache = Arc::new(SharedCacheMutex::new(
+        SharedCacheBuilder {
+            text: "one two three".to_owned(),
+            words_builder: |text| text.split(' ').collect(),
+            hits: 0,
+        }
+        .build(),
+    ));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let cache = Arc::clone(&cache);
+            std::thread::spawn(move || {
+                let count = cache.with_words_locked(|words| words.len());
+                cache.with_hits_mut_locked(|hits| *hits += 1);
+                count
+            })
+        })
+        .collect();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap(), 3);
+    }
+    assert_eq!(cache.with_hits_locked(|hits| *hits), 4);
+    cache.with_mut_locked(|fields| fields.words.retain(|word| word.len() == 3));
+    assert_eq!(
+        cache.with_locked(|fields| (fields.text_contents.len(), fields.words.len())),
+        (13, 2)
+    );
+    let cache = Arc::try_unwrap(cache).ok().unwrap().into_inner();
+    assert_eq!(cache.into_heads().text, "one two three");
+}
+
+#[test]
+fn sync_wrapper_guard() {
+    let mut cache: SharedCacheMutex = SharedCache::new("a b".to_owned(), |text| vec![text], 0).into();
+    cache.get_mut().with_hits_mut(|hits| *hits = 5);
+    let guard = cache.lock();
+    assert_eq!(guard.with_hits(|hits| *hits), 5);
+    assert_eq!(guard.with_words(|words| words.len()), 1);
+}
+
+#[test]
+fn arc_mutex_guard() {
+    let mutex = Arc::new(std::sync::Mutex::new(vec![1]));
+    let mut guard = ouroboros::ArcMutexGuard::lock(Arc::clone(&mutex)).unwrap();
+    guard.push(2);
+    assert!(mutex.try_lock().is_err());
+    let returned = ouroboros::ArcMutexGuard::unlock(guard);
+    assert!(Arc::ptr_eq(&returned, &mutex));
+    assert_eq!(*mutex.lock().unwrap(), [1, 2]);
+}
+
+#[test]
+fn arc_mutex_guard_across_threads() {
+    let mutex = Arc::new(std::sync::Mutex::new(0));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let mutex = Arc::clone(&mutex);
+            std::thread::spawn(move || *ouroboros::ArcMutexGuard::lock(mutex).unwrap() += 1)
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(*ouroboros::ArcMutexGuard::lock(mutex).unwrap(), 4);
+}
+
+#[test]
+fn arc_mutex_guard_poisoned() {
+    let mutex = Arc::new(std::sync::Mutex::new(1));
+    let poisoner = Arc::clone(&mutex);
+    let _ = std::thread::spawn(move || {
+        let _guard = poisoner.lock().unwrap();
+        panic!("Poisoning the mutex.");
+    })
+    .join();
+    let err = ouroboros::ArcMutexGuard::lock(mutex).err().unwrap();
+    assert_eq!(*err.into_inner(), 1);
+}
+
+#[test]
+fn arc_rw_lock_guards() {
+    let lock = Arc::new(std::sync::RwLock::new(String::from("a")));
+    {
+        let mut writer = ouroboros::ArcRwLockWriteGuard::write(Arc::clone(&lock)).unwrap();
+        writer.push('b');
+        assert!(lock.try_read().is_err());
+    }
+    let first = ouroboros::ArcRwLockReadGuard::read(Arc::clone(&lock)).unwrap();
+    let second = ouroboros::ArcRwLockReadGuard::read(Arc::clone(&lock)).unwrap();
+    assert_eq!(&*first, "ab");
+    assert_eq!(&*second, "ab");
+    assert!(lock.try_write().is_err());
+    drop(first);
+    let returned = ouroboros::ArcRwLockReadGuard::unlock(second);
+    assert!(returned.try_write().is_ok());
+}
+
+#[test]
+fn rc_ref_guards() {
+    let cell = Rc::new(std::cell::RefCell::new(vec![1, 2]));
+    let first = ouroboros::RcRef::borrow(Rc::clone(&cell));
+    let second = ouroboros::RcRef::try_borrow(Rc::clone(&cell)).unwrap();
+    assert_eq!(first.len() + second.len(), 4);
+    assert!(ouroboros::RcRefMut::try_borrow_mut(Rc::clone(&cell)).is_err());
+    drop(first);
+    let returned = ouroboros::RcRef::release(second);
+    assert!(Rc::ptr_eq(&returned, &cell));
+
+    let mut writer = ouroboros::RcRefMut::borrow_mut(Rc::clone(&cell));
+    writer.push(3);
+    assert!(ouroboros::RcRef::try_borrow(Rc::clone(&cell)).is_err());
+    drop(writer);
+    assert_eq!(*cell.borrow(), [1, 2, 3]);
+}
+
+#[test]
+fn iterator_field() {
+    let chars = OwnedCharsBuilder {
+        text: "abc".to_owned(),
+        chars_builder: |text| text.chars(),
+    }
+    .build();
+    assert_eq!(chars.size_hint(), (1, Some(3)));
+    assert_eq!(chars.collect::<String>(), "abc");
+}
+
+fn owned_words(text: String) -> ouroboros::OwningIter<String, String> {
+    ouroboros::OwningIter::new(text, |text| Box::new(text.split(' ').map(str::to_owned)))
+}
+
+#[test]
+fn owning_iter() {
+    let mut words = owned_words("one two three".to_owned());
+    assert_eq!(words.next().as_deref(), Some("one"));
+    assert_eq!(words.collect::<Vec<_>>(), ["two", "three"]);
+
+    let windows = ouroboros::OwningIter::new(vec![1, 2, 3], |data: &[i32]| {
+        Box::new(data.windows(2).map(|window| window[0] + window[1]))
+    });
+    assert_eq!(windows.collect::<Vec<_>>(), [3, 5]);
+
+    let mut partial = ouroboros::OwningIter::new(vec![4, 5], |data| Box::new(data.iter().copied()));
+    assert_eq!(partial.next(), Some(4));
+    assert_eq!(partial.into_head(), [4, 5]);
+}
+
+#[test]
+fn delegated_iterator() {
+    let mut values = DelegatedIterator::new(vec![1, 2, 3, 4], |data| data.iter().copied());
+    assert_eq!(values.len(), 4);
+    assert_eq!(values.next_back(), Some(4));
+    assert_eq!(values.next(), Some(1));
+    assert_eq!(values.collect::<Vec<_>>(), [2, 3]);
+}
+
+#[test]
+fn delegated_io() {
+    use std::io::{BufRead, Read, Write};
+    let mut io = DelegatedIo::new(
+        b"first\nsecond".to_vec(),
+        Vec::new(),
+        |input| std::io::Cursor::new(input),
+        |output| output,
+    );
+    let mut line = String::new();
+    io.read_line(&mut line).unwrap();
+    assert_eq!(line, "first\n");
+    let mut rest = String::new();
+    io.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "second");
+    write!(io, "{}-{}", line.trim(), rest).unwrap();
+    io.flush().unwrap();
+    assert_eq!(io.with_writer(|writer| writer.to_vec()), b"first-second");
+}
+
+#[test]
+fn delegated_formatting() {
+    let value = DelegatedFormatting::new("  text  ".to_owned(), |text| text.trim());
+    assert_eq!(format!("[{}]", value), "[text]");
+    assert_eq!(format!("{:?}", value), "\"text\"");
+}
+
+#[test]
+fn delegated_listed_trait() {
+    let mut tally = DelegatedListedTrait::new(vec![1, 2, 3, 4], |data| data.iter().collect());
+    assert_eq!(tally.total(), 10);
+    tally.forget_first(2);
+    assert_eq!(tally.total(), 7);
+    assert_eq!(tally.with_data(|data| data.len()), 4);
+}
+
+fn block_on<F: std::future::Future>(mut future: Pin<&mut F>) -> F::Output {
+    let mut cx = Context::from_waker(std::task::Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+#[test]
+fn pinned_future() {
+    let mut future = Box::pin(PinnedFuture::new(vec![1, 2, 3], |data| SumSlowly {
+        data,
+        index: 0,
+        sum: 0,
+        _pinned: std::marker::PhantomPinned,
+    }));
+    assert_eq!(block_on(future.as_mut()), 6);
+}
+
+#[test]
+fn pin_mut_accessor() {
+    let mut future = Box::pin(PinnedFuture::new(vec![4], |data| SumSlowly {
+        data,
+        index: 0,
+        sum: 0,
+        _pinned: std::marker::PhantomPinned,
+    }));
+    let mut cx = Context::from_waker(std::task::Waker::noop());
+    let poll = future
+        .as_mut()
+        .with_sum_pin_mut(|sum| std::future::Future::poll(sum, &mut cx));
+    assert_eq!(poll, Poll::Pending);
+    assert_eq!(future.with_sum(|sum| sum.index), 1);
+}
+
+#[test]
+fn boxed_future() {
+    let mut future = BoxedFuture::new("request body".to_owned(), |body| {
+        Box::pin(async move { body.len() })
+    });
+    // The boxed future is Unpin, so the struct is as well.
+    assert_eq!(block_on(Pin::new(&mut future)), 12);
+}
+
+#[test]
+fn delegated_stream() {
+    use futures_core::Stream;
+    let mut stream = WordStream::new("a stream of words".to_owned(), |text| {
+        Words(text.split_whitespace())
+    });
+    let mut cx = Context::from_waker(std::task::Waker::noop());
+    let mut words = Vec::new();
+    while let Poll::Ready(Some(word)) = Pin::new(&mut stream).poll_next(&mut cx) {
+        words.push(word);
+    }
+    assert_eq!(words, ["a", "stream", "of", "words"]);
+}
+
+#[test]
+fn enum_variant_constructors() {
+    let header = DecoderState::new_header(vec![1, 2, 3, 4].into_boxed_slice(), |buffer| {
+        &buffer[..2]
+    });
+    assert_eq!(header.with_header(|fields| fields.magic.to_vec()), Some(vec![1, 2]));
+    assert_eq!(header.with_body(|_| ()), None);
+
+    let built: DecoderState = DecoderStateBodyBuilder {
+        text: "hello".to_owned(),
+        cursor_builder: |text| &mut text[1..],
+    }
+    .build()
+    .into();
+    assert!(built.with_header(|_| ()).is_none());
+    assert_eq!(
+        built.with_body(|fields| fields.cursor.to_string()),
+        Some("ello".to_owned())
+    );
+}
+
+#[test]
+fn enum_with_variant_mut() {
+    let mut state = DecoderState::new_body("hello".to_owned(), |text| &mut text[..]);
+    state.with_body_mut(|fields| fields.cursor.make_ascii_uppercase());
+    assert_eq!(
+        state.with_body(|fields| fields.cursor.to_string()),
+        Some("HELLO".to_owned())
+    );
+}
+
+#[test]
+fn enum_match_with() {
+    let states = [
+        DecoderState::Idle,
+        DecoderState::new_header(vec![7, 8].into_boxed_slice(), |buffer| &buffer[1..]),
+        DecoderState::new_body("text".to_owned(), |text| &mut text[2..]),
+        DecoderState::Done(3),
+    ];
+    let summaries: Vec<_> = states
+        .iter()
+        .map(|state| {
+            state.match_with(|fields| match fields {
+                DecoderStateBorrowed::Idle => "idle".to_owned(),
+                DecoderStateBorrowed::Header(fields) => format!("{:?}", fields.magic),
+                // The cursor mutably borrows the text, so only tails are visible here.
+                DecoderStateBorrowed::Body(_) => "body".to_owned(),
+                DecoderStateBorrowed::Done(code) => code.to_string(),
+            })
+        })
+        .collect();
+    assert_eq!(summaries, ["idle", "[8]", "body", "3"]);
+}
+
+#[test]
+fn enum_match_with_mut() {
+    let mut state = DecoderState::Done(1);
+    state.match_with_mut(|fields| {
+        if let DecoderStateBorrowedMut::Done(code) = fields {
+            *code += 1;
+        }
+    });
+    assert!(matches!(state, DecoderState::Done(2)));
+}
+
+#[test]
+fn enum_into_heads() {
+    let state = DecoderState::new_header(vec![1, 2].into_boxed_slice(), |buffer| buffer);
+    match state.into_heads() {
+        DecoderStateHeads::Header(heads) => assert_eq!(&*heads.buffer, &[1, 2]),
+        _ => panic!("Test failed."),
+    }
+    assert!(matches!(
+        DecoderState::Done(5).into_heads(),
+        DecoderStateHeads::Done(5)
+    ));
+}
+
+#[test]
+fn generic_enum() {
+    let value = GenericEnum::new_value(Box::new("text"), |data| data);
+    assert_eq!(value.with_value(|fields| **fields.dref), Some("text"));
+    assert!(GenericEnum::<i32>::Empty.with_value(|_| ()).is_none());
+}
+
+#[test]
+fn partly_generic_enum() {
+    let value = PartlyGenericEnum::<'_, i32>::new_count(vec![7, 8], |items| items.first());
+    assert_eq!(value.with_count(|fields| fields.first.copied()), Some(Some(7)));
+    let value = PartlyGenericEnum::new_value(Box::new(5), |data| data);
+    assert_eq!(value.with_value(|fields| **fields.dref), Some(5));
+    let named: PartlyGenericEnum<'_, i32> = PartlyGenericEnum::Named { name: "name" };
+    assert!(named.with_count(|_| ()).is_none());
+    let name = named.match_with(|fields| match fields {
+        PartlyGenericEnumBorrowed::Named { name } => *name,
+        _ => "",
+    });
+    assert_eq!(name, "name");
+}
+
+#[test]
+fn enum_custom_lifetime() {
+    let value = CustomLifetimeEnum::new_words("a b".to_owned(), |buffer| {
+        buffer.split(' ').collect()
+    });
+    assert_eq!(value.with_words(|fields| fields.words.len()), Some(2));
+    assert!(CustomLifetimeEnum::Empty.with_words(|_| ()).is_none());
+}
+
 #[test]
 fn box_and_mut_ref() {
     let mut bar = BoxAndMutRefBuilder {
@@ -98,6 +1473,37 @@ fn box_and_mut_ref() {
     assert!(bar.with_dref(|dref| **dref) == 34);
 }
 
+#[test]
+fn restricted_visibility() {
+    let nested = nested::Nested::new(vec![1, 2, 3], |data| data.last());
+    assert_eq!(nested.with_last(|last| last.copied()), Some(3));
+    let heads: nested::NestedHeads = nested.into_heads();
+    assert_eq!(heads.data, vec![1, 2, 3]);
+}
+
+#[test]
+fn similar_names() {
+    let upper = HTTPCache::new("upper".to_owned(), |data| &data[..2]);
+    let lower = HttpCache::new("lower".to_owned(), |data| &data[..2]);
+    assert_eq!(upper.with_view(|view| *view), "up");
+    assert_eq!(lower.with_view(|view| *view), "lo");
+}
+
+#[test]
+fn fn_local_struct() {
+    struct Local(i32);
+
+    #[self_referencing]
+    struct Holder {
+        values: Vec<Local>,
+        #[borrows(values)]
+        first: &'this Local,
+    }
+
+    let holder = Holder::new(vec![Local(4), Local(5)], |values| &values[0]);
+    assert_eq!(holder.with_first(|first| first.0), 4);
+}
+
 #[cfg(not(feature = "miri"))]
 mod compile_tests {
     /// Tests that all files in fail_tests fail to compile.
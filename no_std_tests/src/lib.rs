@@ -0,0 +1,128 @@
+//! Checks that the code generated by `#[self_referencing]` compiles in a `no_std` crate which only
+//! has access to `alloc`.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Display, Formatter};
+use core::future::Future;
+use core::pin::Pin;
+use ouroboros::{self_referencing, OwningIter, RcRef};
+
+#[self_referencing]
+pub struct BoxAndRef {
+    data: Box<i32>,
+    #[borrows(data)]
+    dref: &'this i32,
+}
+
+#[self_referencing]
+pub struct Chain {
+    text: String,
+    #[borrows(text)]
+    words: Vec<&'this str>,
+    #[borrows(words)]
+    first: Option<&'this &'this str>,
+}
+
+#[self_referencing]
+pub struct ContainerBorrow {
+    data: Vec<u8>,
+    #[borrows(&data)]
+    container: &'this Vec<u8>,
+    buffer: Box<[u8]>,
+    #[borrows(mut buffer)]
+    buffer_ref: &'this mut [u8],
+}
+
+#[self_referencing]
+pub struct OptionalHead {
+    data: Option<Box<str>>,
+    #[borrows(data)]
+    view: Option<&'this str>,
+}
+
+#[self_referencing]
+pub enum Decoder {
+    Idle { count: u32 },
+    Reading {
+        buffer: Box<[u8]>,
+        #[borrows(buffer)]
+        rest: &'this [u8],
+    },
+}
+
+struct Label<'a>(&'a str);
+
+impl Display for Label<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+#[self_referencing]
+pub struct Delegated {
+    text: String,
+    #[borrows(text)]
+    #[ouroboros(iterator)]
+    chars: core::str::Chars<'this>,
+    #[borrows(text)]
+    #[ouroboros(delegate(core::fmt::Display))]
+    label: Label<'this>,
+}
+
+#[self_referencing]
+pub struct PinnedFuture {
+    value: Box<u32>,
+    #[borrows(value)]
+    #[ouroboros(future)]
+    future: Pin<Box<dyn Future<Output = u32> + 'this>>,
+}
+
+pub fn use_generated_code() -> usize {
+    let simple = BoxAndRefBuilder {
+        data: Box::new(12),
+        dref_builder: |data| data,
+    }
+    .build();
+    let chain = ChainBuilder {
+        text: String::from("hello no_std"),
+        words_builder: |text| text.split(' ').collect(),
+        first_builder: |words| words.first(),
+    }
+    .build();
+    let mut container = ContainerBorrow::new(
+        Vec::from([1, 2, 3]),
+        |data| data,
+        Box::new([0; 4]),
+        |buffer| buffer,
+    );
+    container.with_buffer_ref_mut(|buffer| buffer[0] = 1);
+    let optional = OptionalHead::new(None, |data| data);
+    let decoder = Decoder::new_reading(Box::new([1, 2]), |buffer| &buffer[1..]);
+    let delegated = Delegated::new(String::from("abc"), |text| text.chars(), |text| Label(text));
+    let iter = OwningIter::new(Vec::from([1, 2, 3]), |v| Box::new(v.iter().copied()));
+    let cell = RcRef::borrow(alloc::rc::Rc::new(core::cell::RefCell::new(5)));
+    let future = PinnedFuture::new(Box::new(3), |value| Box::pin(async move { *value }));
+    drop(future);
+    simple.with_dref(|dref| **dref as usize)
+        + chain.with_first(|first| first.map_or(0, |word| word.len()))
+        + container.with_container(|c| c.len())
+        + optional.with_view(|view| view.map_or(0, str::len))
+        + decoder.with_reading(|fields| fields.rest.len()).unwrap_or(0)
+        + delegated.count()
+        + iter.sum::<usize>()
+        + *cell as usize
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn generated_code_works() {
+        assert_eq!(super::use_generated_code(), 35);
+    }
+}
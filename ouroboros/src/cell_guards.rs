@@ -0,0 +1,89 @@
+//! Owned versions of the guards returned by `RefCell`. Each type stores the `Rc` containing the
+//! cell alongside a guard borrowing from it, so that the guard can be returned from functions or
+//! stored in other structs.
+//!
+//! The contents of the cell have to be `'static`, since these types have no lifetime parameter
+//! for them to outlive instead.
+
+use crate::self_referencing;
+use alloc::rc::Rc;
+use core::cell::{BorrowError, BorrowMutError, Ref, RefCell, RefMut};
+use core::ops::{Deref, DerefMut};
+
+#[self_referencing]
+/// An owned version of [`Ref`], created by [`RcRef::borrow()`]. The `RefCell` is no longer
+/// borrowed once this is dropped.
+pub struct RcRef<T: 'static> {
+    cell: Rc<RefCell<T>>,
+    #[borrows(cell)]
+    guard: Ref<'this, T>,
+}
+
+impl<T: 'static> RcRef<T> {
+    /// Immutably borrows the contents of the `RefCell`. Panics if it is currently mutably
+    /// borrowed, like [`RefCell::borrow()`].
+    pub fn borrow(cell: Rc<RefCell<T>>) -> Self {
+        Self::new(cell, |cell| cell.borrow())
+    }
+
+    /// Immutably borrows the contents of the `RefCell`, returning an error if it is currently
+    /// mutably borrowed.
+    pub fn try_borrow(cell: Rc<RefCell<T>>) -> Result<Self, BorrowError> {
+        Self::try_new(cell, |cell| cell.try_borrow())
+    }
+
+    /// Releases the borrow and returns the `Rc` it was taken from.
+    pub fn release(this: Self) -> Rc<RefCell<T>> {
+        this.into_heads().cell
+    }
+}
+
+impl<T: 'static> Deref for RcRef<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.with_guard(|guard| &**guard)
+    }
+}
+
+#[self_referencing]
+/// An owned version of [`RefMut`], created by [`RcRefMut::borrow_mut()`]. The `RefCell` is no
+/// longer borrowed once this is dropped.
+pub struct RcRefMut<T: 'static> {
+    cell: Rc<RefCell<T>>,
+    #[borrows(cell)]
+    guard: RefMut<'this, T>,
+}
+
+impl<T: 'static> RcRefMut<T> {
+    /// Mutably borrows the contents of the `RefCell`. Panics if it is currently borrowed, like
+    /// [`RefCell::borrow_mut()`].
+    pub fn borrow_mut(cell: Rc<RefCell<T>>) -> Self {
+        Self::new(cell, |cell| cell.borrow_mut())
+    }
+
+    /// Mutably borrows the contents of the `RefCell`, returning an error if it is currently
+    /// borrowed.
+    pub fn try_borrow_mut(cell: Rc<RefCell<T>>) -> Result<Self, BorrowMutError> {
+        Self::try_new(cell, |cell| cell.try_borrow_mut())
+    }
+
+    /// Releases the borrow and returns the `Rc` it was taken from.
+    pub fn release(this: Self) -> Rc<RefCell<T>> {
+        this.into_heads().cell
+    }
+}
+
+impl<T: 'static> Deref for RcRefMut<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.with_guard(|guard| &**guard)
+    }
+}
+
+impl<T: 'static> DerefMut for RcRefMut<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.with_guard_mut(|guard| &mut **guard)
+    }
+}
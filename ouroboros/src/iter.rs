@@ -0,0 +1,35 @@
+//! An iterator which owns the data it iterates over.
+
+use crate::self_referencing;
+use alloc::boxed::Box;
+use stable_deref_trait::StableDeref;
+
+#[self_referencing]
+/// An iterator which owns the data it iterates over, so that it can be returned from functions
+/// or stored in other structs. The iterator is created from a reference to the contents of `head`
+/// and must be boxed since its type usually cannot be named:
+/// ```rust
+/// use ouroboros::OwningIter;
+///
+/// fn letters(text: String) -> OwningIter<String, char> {
+///     OwningIter::new(text, |text| Box::new(text.chars().filter(|c| c.is_alphabetic())))
+/// }
+///
+/// assert_eq!(letters("a1b2".to_owned()).collect::<String>(), "ab");
+/// ```
+/// The items cannot borrow from `head`. Map them to owned values if needed, E.G.
+/// `text.split(' ').map(str::to_owned)`.
+/// Since the struct has no lifetime parameter, `H` and `Item` have to be `'static`.
+pub struct OwningIter<H: StableDeref + 'static, Item: 'static> {
+    head: H,
+    #[borrows(head)]
+    #[ouroboros(iterator)]
+    iter: Box<dyn Iterator<Item = Item> + 'this>,
+}
+
+impl<H: StableDeref + 'static, Item: 'static> OwningIter<H, Item> {
+    /// Drops the iterator and returns the data it was iterating over.
+    pub fn into_head(self) -> H {
+        self.into_heads().head
+    }
+}
@@ -3,9 +3,38 @@
 //! See the documentation for [`#[self_referencing]`](self_referencing) to get started.
 //! See the documentation of [`ouroboros_examples`](https://docs.rs/ouroboros_examples) for
 //! sample documentation of structs which have had the macro applied to them.
+//!
+//! The crate also provides owned versions of common guard types which are built using the macro:
+//! [`ArcMutexGuard`], [`ArcRwLockReadGuard`], [`ArcRwLockWriteGuard`], [`RcRef`] and [`RcRefMut`].
+//! Each one stores the `Arc` or `Rc` alongside the guard borrowing from it and dereferences to the
+//! guarded value. [`OwningIter`] similarly stores an iterator alongside the data it iterates over.
+//!
+//! # Features
+//!
+//! - `std` (enabled by default): provides the `Mutex` and `RwLock` guard types and allows using
+//!   `#[self_referencing(sync)]` and delegating `std::io` traits. Without it the crate is `no_std`
+//!   and only requires `alloc`, as does the code generated by the macro.
+//! - `stream`: allows delegating `futures::Stream` to a field with `#[ouroboros(stream)]`.
 
+#![no_std]
 #![allow(clippy::needless_doctest_main)]
 
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+// Lets the code generated by `#[self_referencing]` refer to `::ouroboros` from inside this crate.
+extern crate self as ouroboros;
+
+mod cell_guards;
+mod iter;
+#[cfg(feature = "std")]
+mod lock_guards;
+pub use cell_guards::*;
+pub use iter::OwningIter;
+#[cfg(feature = "std")]
+pub use lock_guards::*;
+
 /// This macro is used to turn a regular struct into a self-referencing one. An example:
 /// ```rust
 /// use ouroboros::self_referencing;
@@ -59,13 +88,35 @@
 /// `#[borrows()]` on top and place inside the parenthesis a list of fields that it borrows. Mut can
 /// be prefixed to indicate that a mutable borrow is required. For example,
 /// `#[borrows(a, b, mut c)]` indicates that the first two fields need to be borrowed immutably and
-/// the third needs to be borrowed mutably.
+/// the third needs to be borrowed mutably. A field can also have several `#[borrows()]`
+/// attributes, which is the same as listing all of their fields in a single one, E.G.
+/// `#[borrows(a, b)]` followed by `#[borrows(mut c)]`. Borrowing the same field twice is an error.
+///
+/// By default, a field receives a reference to the *contents* of the fields it borrows, E.G. a
+/// `&'this i32` for a field of type `Box<i32>`. Prefixing a field with `&` borrows the field
+/// itself instead, E.G. `#[borrows(&a, &mut b)]` provides a `&'this Vec<T>` for a field
+/// `a: Vec<T>` rather than a `&'this [T]`. Fields borrowed this way do not need to implement
+/// `StableDeref`, they are stored on the heap internally so that they never move.
+///
+/// Fields can be declared in any order. They are constructed after every field they borrow and
+/// dropped before them, while the arguments of constructors and builders still follow the order
+/// the fields were declared in. The fields are also stored in the order they were declared in, so
+/// `#[repr(C)]` and other attributes which depend on the layout of the struct work as usual. To
+/// control the order fields are dropped in, the macro implements `Drop` for the struct, so it
+/// cannot implement `Drop` itself. Use the `on_drop` option described below instead.
 /// # You must comply with these limitations
-/// - Fields must be declared before the first time they are borrowed.
+/// - Fields cannot borrow each other in a cycle, E.G. `a` cannot borrow `b` if `b` borrows `a`.
 /// - Normal borrowing rules apply, E.G. a field cannot be borrowed mutably twice.
+/// - Only fields with `#[borrows(...)]` can use `'this`. The macro also warns about tail fields
+///   which borrow other fields but never mention `'this`, and about `&'this mut` references in
+///   fields which only borrow immutably.
 /// - Fields that are borrowed must be of a data type that implement
 ///   [`StableDeref`](https://docs.rs/stable_deref_trait/1.2.0/stable_deref_trait/trait.StableDeref.html).
-///   Normally this just means `Box<T>`.
+///   Normally this just means `Box<T>`, but `Vec<T>`, `String`, `Rc<T>`, `Arc<T>` and `Box<[T]>`
+///   all work as well. This includes self-referencing fields which are borrowed by later fields,
+///   E.G. a `Vec<&'this str>` can itself be borrowed as a `&'this [&'this str]`. Fields declared
+///   as `Option<T>` where `T` implements `StableDeref` can be borrowed as well, in which case
+///   builders receive an `Option<&T::Target>` and `with_FIELD_contents` provides one.
 ///
 /// Violating them will result in a nice error message directly pointing out the violated rule.
 /// # Flexibility of this crate
@@ -119,25 +170,193 @@
 ///     });
 /// }
 /// ```
-/// # Using `chain_hack`
-/// Unfortunately, as of September 2020, Rust has a
-/// [known limitation in its type checker](https://users.rust-lang.org/t/why-does-this-not-compile-box-t-target-t/49027/7?u=aaaaa)
-/// which prevents chained references from working (I.E. structs where field C references field B
-/// which references field A.) To counteract this problem, you can use
-/// `#[self_referencing(chain_hack)]` to allow creating these kinds of structs at the cost of
-/// additional restrictions and possible loss of clarity in some error messages. The main limitation
-/// is that all fields that are borrowed must be of type `Box<T>`. A nice error message will be
-/// generated if you use a different type. There should be no other limitations, but some
-/// configurations may produce strange compiler errors. If you find such a configuration, please
-/// open an issue on the [Github repository](https://github.com/joshua-maros/ouroboros/issues).
-/// You can view a documented example of a struct which uses `chain_hack` [here](https://docs.rs/ouroboros_examples/latest/ouroboros_examples/struct.ChainHack.html).
+/// # Chained borrows and `chain_hack`
+/// Fields which borrow other fields can themselves be borrowed, E.G. a struct where field C
+/// references field B which references field A. Any container implementing `StableDeref` can be
+/// used at every step of the chain, including type aliases and containers whose contents mention
+/// `'this`:
+/// ```rust
+/// use ouroboros::self_referencing;
+///
+/// #[self_referencing]
+/// struct Chain {
+///     a: Box<i32>,
+///     #[borrows(a)]
+///     b: Vec<&'this i32>,
+///     #[borrows(b)]
+///     c: &'this [&'this i32],
+/// }
+/// ```
+/// Older versions of this crate required `#[self_referencing(chain_hack)]` to work around a
+/// limitation in Rust's type checker, which restricted every borrowed field to `Box<T>`. The option
+/// is still accepted but no longer does anything and produces a deprecation warning.
+/// # Generic parameters
+/// Structs can have type and lifetime parameters. Heads which are borrowed only need to outlive
+/// the shortest lifetime parameter of the struct, as described below, so a generic head can
+/// contain non-`'static` data if the struct declares a lifetime for it. A struct without lifetime
+/// parameters has nothing shorter than `'static` to use, so every type parameter used by a head
+/// or a self-referencing field has to be `'static`, E.G. `struct Pairs<T: 'static>`. Adding a
+/// lifetime parameter lifts this requirement, as `Pairs` and `FirstHalf` do here:
+/// ```rust
+/// use ouroboros::self_referencing;
+///
+/// #[self_referencing]
+/// struct Pairs<'a, T: 'a> {
+///     items: Vec<T>,
+///     #[borrows(items)]
+///     pairs: Vec<(&'this T, &'this T)>,
+/// }
+///
+/// #[self_referencing]
+/// struct FirstHalf<'a> {
+///     bytes: &'a [u8],
+///     #[borrows(bytes)]
+///     half: &'this [u8],
+/// }
+///
+/// fn main() {
+///     let text = String::from("a b c");
+///     let pairs = Pairs::new(text.split(' ').collect(), |items| {
+///         items.windows(2).map(|pair| (&pair[0], &pair[1])).collect()
+///     });
+///     assert_eq!(pairs.with_pairs(|pairs| pairs.len()), 2);
+///     let half = FirstHalf::new(text.as_bytes(), |bytes| &bytes[..bytes.len() / 2]);
+///     assert_eq!(half.with_half(|half| half.len()), 2);
+/// }
+/// ```
+/// If a generic parameter is only used by fields which borrow other fields, as `'a` is in `Pairs`,
+/// the builder structs do not take it and `build()` declares it instead, so builders are written
+/// the same way as for any other struct. The `PairsHeads` struct has an additional hidden field
+/// `_phantom` for it.
+/// Internally, `'this` is replaced with the shortest lifetime parameter of the struct, which is the
+/// one the bounds of the struct declare every other lifetime to outlive, or the first one if the
+/// bounds do not single one out. No bounds are added between the struct's own lifetimes. A field
+/// which puts data with another lifetime behind a `'this` reference, E.G. `&'this &'b T`, still
+/// needs that lifetime to outlive the replaced one, which Rust infers from the field. Declaring a
+/// bound such as `struct S<'b: 'a, 'a>` makes `'a` the replaced lifetime instead. The structs
+/// passed to `with` and `with_mut` state that `'this` is shorter than every lifetime which
+/// outlives the replaced one. Builders and `with_*` functions take functions which work for any
+/// `'this`, and Rust cannot put bounds on such lifetimes, so they only know how `'this` relates to
+/// other lifetimes through the types of their arguments. A field which contains data with an
+/// external lifetime should therefore name that lifetime separately, as `Tokens<'this, 'a>` does
+/// here:
+/// ```rust
+/// use ouroboros::self_referencing;
+///
+/// pub struct Config {
+///     separator: char,
+/// }
+///
+/// pub struct Tokens<'t, 'c> {
+///     config: &'c Config,
+///     words: Vec<&'t str>,
+/// }
+///
+/// #[self_referencing]
+/// struct Ctx<'a> {
+///     config: &'a Config,
+///     buffer: Box<str>,
+///     #[borrows(buffer)]
+///     tokens: Tokens<'this, 'a>,
+/// }
+///
+/// fn main() {
+///     let config = Config { separator: ',' };
+///     let ctx = Ctx::new(&config, "a,b".into(), |buffer| Tokens {
+///         config: &config,
+///         words: buffer.split(config.separator).collect(),
+///     });
+///     assert_eq!(ctx.with_tokens(|tokens| tokens.words.len()), 2);
+/// }
+/// ```
+/// # Running code when the struct is dropped
+/// `#[self_referencing(on_drop = path)]` calls the function at `path` right before the fields are
+/// dropped, either because the struct is dropped or because `into_heads` is called. It receives
+/// the same fields as `with_mut`, through the generated `BufferedBorrowedMutFields` struct. Since
+/// the function could move out of the fields it receives, `on_drop` cannot be combined with
+/// pinned fields.
+/// ```rust
+/// use ouroboros::self_referencing;
+/// use std::io::Write;
+///
+/// fn flush(fields: BufferedBorrowedMutFields<'_, '_>) {
+///     fields.output.write_all(fields.pending).unwrap();
+/// }
+///
+/// #[self_referencing(on_drop = flush)]
+/// struct Buffered {
+///     data: Vec<u8>,
+///     #[borrows(data)]
+///     pending: &'this [u8],
+///     output: Vec<u8>,
+/// }
+///
+/// fn main() {
+///     let buffered = Buffered::new(b"text".to_vec(), |data| &data[..], Vec::new());
+///     drop(buffered);
+/// }
+/// ```
+/// # Naming the internal lifetime
+/// `'this` is only special when it is used as a lifetime, so types, modules and macro arguments
+/// named `this` can be used in fields as usual. If `'this` is not a good fit, a different name can
+/// be chosen with `#[self_referencing(lifetime = 'x)]`, in which case `'x` must be used in its
+/// place:
+/// ```rust
+/// use ouroboros::self_referencing;
+///
+/// #[self_referencing(lifetime = 'buf)]
+/// struct Words {
+///     buffer: String,
+///     #[borrows(buffer)]
+///     words: Vec<&'buf str>,
+/// }
+/// ```
+/// # Enums
+/// `#[self_referencing]` can also be applied to an enum. Every variant with named fields which
+/// uses `#[borrows]` is turned into a self-referencing struct named after the enum and the variant,
+/// E.G. `DecoderHeader` below, and the variant wraps that struct. Other variants are left as they
+/// were written:
+/// ```rust
+/// use ouroboros::self_referencing;
+///
+/// #[self_referencing]
+/// enum Decoder {
+///     Idle,
+///     Header {
+///         buffer: Box<[u8]>,
+///         #[borrows(buffer)]
+///         magic: &'this [u8],
+///     },
+/// }
+///
+/// fn main() {
+///     let decoder = Decoder::new_header(vec![1, 2, 3].into_boxed_slice(), |buffer| &buffer[..2]);
+///     assert_eq!(decoder.with_header(|fields| fields.magic.len()), Some(2));
+///     decoder.match_with(|fields| match fields {
+///         DecoderBorrowed::Idle => println!("Idle"),
+///         DecoderBorrowed::Header(fields) => println!("Magic: {:?}", fields.magic),
+///     });
+/// }
+/// ```
+/// For every self-referencing variant, `MyEnum::new_VARIANT(fields...)` constructs it using the same
+/// arguments as the generated struct's `new()`, and `with_VARIANT`/`with_VARIANT_mut` call `with`
+/// or `with_mut` on it, returning `None` if a different variant is active. The generated struct
+/// can also be built using its own builders and converted with `.into()`. It only takes the
+/// generic parameters of the enum which its fields use. `match_with` and
+/// `match_with_mut` provide references to the fields of whichever variant is active through the
+/// `MyEnumBorrowed` and `MyEnumBorrowedMut` enums, and `into_heads` returns a `MyEnumHeads` enum
+/// containing the **head fields** of the active variant.
 /// # What does the macro generate?
 /// The `#[self_referencing]` struct will replace your definition with an unsafe self-referencing
 /// struct with a safe public interface. Many functions will be generated depending on your original
 /// struct definition. Documentation is generated for all items, so building documentation for
 /// your project allows accessing detailed information about available functions. Using 
 /// `#[self_referencing(no_doc)]` will hide the generated items from documentation if it is becoming 
-/// too cluttered. The following is an overview of what is generated:
+/// too cluttered. Every generated item is placed right next to the struct and has the same
+/// visibility as it, so structs declared inside functions work like any other. The fields of the
+/// struct are moved into a private storage struct which can only be reached through unsafe code,
+/// so even the module declaring the struct can only access them through the generated methods.
+/// The following is an overview of what is generated:
 /// ### `MyStruct::new(fields...) -> MyStruct`
 /// A basic constructor. It accepts values for each field in the order you declared them in. For
 /// **head fields**, you only need to pass in what value it should have and it will be moved in
@@ -157,14 +376,15 @@
 /// **self-referencing fields** can return `Result<>`s. If any of those are `Err`s, that error will be
 /// returned instead of an instance of `MyStruct`. The preferred way to use this function is through
 /// `MyStructTryBuilder` and its `try_build()` function.
-/// ### `MyStruct::try_new_or_recover<E>(fields...) -> Result<MyStruct, (E, Heads)>`
+/// ### `MyStruct::try_new_or_recover<E>(fields...) -> Result<MyStruct, (E, MyStructHeads)>`
 /// Similar to the `try_new()` function, except that all the **head fields** are returned along side
 /// the original error in case of an error. The preferred way to use this function is through
 /// `MyStructTryBuilder` and its `try_build_or_recover()` function.
 /// ### `MyStruct::with_FIELD<R>(&self, user: FnOnce(field: &FieldType) -> R) -> R`
-/// This function is generated for every **tail field** in your struct. It allows safely accessing
-/// a reference to that value. The function generates the reference and passes it to `user`. You
-/// can do anything you want with the reference, it is constructed to not outlive the struct.
+/// This function is generated for every **tail field** and **immutably borrowed field** in your
+/// struct. It allows safely accessing a reference to that value. The function generates the
+/// reference and passes it to `user`. You can do anything you want with the reference, it is
+/// constructed to not outlive the struct.
 /// ### `MyStruct::with_FIELD_mut<R>(&mut self, user: FnOnce(field: &mut FieldType) -> R) -> R`
 /// This function is generated for every **tail field** in your struct. It is the mutable version
 /// of `with_FIELD`.
@@ -174,36 +394,204 @@
 /// a reference to the field's content, not the field itself. E.G. a field of type `Box<i32>` would
 /// cause this function to provide a reference of type `&i32`. There is no mutable version of this
 /// function because if a field is already borrowed, it cannot be mutably borrowed safely.
-/// ### `MyStruct::with<R>(&self, user: FnOnce(fields: AllFields) -> R) -> R`
+/// `with_FIELD` can be used instead to access the container itself, for example to call
+/// `Vec::capacity()` or `Rc::strong_count()`.
+/// ### `MyStruct::replace_FIELD(&mut self, new_value: FieldType, builders...) -> FieldType`
+/// This function is generated for every **head field** which is borrowed by other fields. It
+/// drops every field that borrows `FIELD`, directly or through other fields, replaces `FIELD` with
+/// `new_value` and then rebuilds those fields using the provided builders, which take the same
+/// arguments as the ones passed to `new()`. The old value of `FIELD` is returned. If one of the
+/// builders panics, the process is aborted since the struct cannot be left partially built.
+/// ### `MyStruct::with<R>(&self, user: FnOnce(fields: MyStructBorrowedFields) -> R) -> R`
 /// Allows borrowing all **tail and immutably-borrowed fields** at once. Functions similarly to
-/// `with_FIELD`.
-/// ### `MyStruct::with_mut<R>(&self, user: FnOnce(fields: AllFields) -> R) -> R`
+/// `with_FIELD`. Immutably borrowed fields are provided both as `FIELD` and as `FIELD_contents`.
+/// ### `MyStruct::with_mut<R>(&mut self, user: FnOnce(fields: MyStructBorrowedMutFields) -> R) -> R`
 /// Allows mutably borrowing all **tail fields** at once. Functions similarly to `with_FIELD_mut`.
-/// ### `MyStruct::into_heads(self) -> Heads`
+/// The contents of **immutably borrowed fields** are provided as well, using the same names as
+/// in `with`, since they can only ever be shared.
+/// ### `MyStruct::into_heads(self) -> MyStructHeads`
 /// Drops all self-referencing fields and returns a struct containing all **head fields**.
+/// ### Delegated trait implementations
+/// Placing `#[ouroboros(delegate(Trait, ...))]` on a field implements each listed trait for the
+/// struct by forwarding its methods to that field through `with_FIELD` or `with_FIELD_mut`.
+/// `#[ouroboros(iterator)]` is shorthand for `#[ouroboros(delegate(Iterator))]`. The traits known
+/// to the macro are `Iterator`, `DoubleEndedIterator`, `ExactSizeIterator`, `std::io::Read`,
+/// `std::io::BufRead`, `std::io::Write`, `std::fmt::Display`, `std::fmt::Debug`,
+/// `std::future::Future` and `futures::Stream`. `#[ouroboros(future)]` and `#[ouroboros(stream)]`
+/// are shorthand for delegating the last two, which pins the field as described below. Delegating
+/// `Stream` requires enabling this crate's `stream` feature. Since the macro cannot see the
+/// definitions of other traits, their methods have to be listed instead, E.G.
+/// `delegate(MyTrait<u32> { fn get(&self) -> u32; fn set(&mut self, value: u32); })`. Listed
+/// methods must take `&self` or `&mut self` and cannot have a body, every other item of the trait
+/// needs a default. Traits which require mutable access can only be delegated to **tail fields**.
+/// Since values are returned through the accessors, the items of a delegated iterator cannot
+/// borrow from the struct. [`OwningIter`] is a ready-made struct using this which can hold any
+/// boxed iterator.
+/// ### `#[derive(Debug)]`
+/// Deriving `Debug` generates an implementation which prints the fields under their declared
+/// names and in their declared order, through `with`. Mutably borrowed fields cannot be accessed
+/// and are left out. No other traits can be derived, since the derived implementations would see
+/// the fields as they are stored. On enums, the derives are applied to the structs generated for
+/// self-referencing variants as well.
+/// ### `MyStruct::with_FIELD_pin_mut<R>(self: Pin<&mut Self>, user: FnOnce(field: Pin<&mut FieldType>) -> R) -> R`
+/// Only generated for **tail fields** marked with `#[ouroboros(pin)]` or delegating `Future` or
+/// `Stream`. Such fields are structurally pinned: pinning the struct pins the field, so this
+/// provides a pinned reference to it. To uphold the guarantees of pinning, the struct only
+/// implements `Unpin` if all of its pinned fields do. The struct cannot implement `Drop` itself
+/// either way, the `Drop` implementation generated by the macro never moves pinned fields.
+/// ### `MyStruct::FIELD_ptr(&self) -> *const FieldType`
+/// Only generated for `#[repr(C)]` structs, along with `FIELD_mut_ptr(&mut self) -> *mut FieldType`
+/// for **tail fields**. Returns a raw pointer to the field, E.G. to hand the struct or parts of it
+/// to foreign code. Since fields keep their declared order, a pointer to the struct can be passed
+/// to C code expecting the same layout, keeping in mind that fields which are borrowed as a whole
+/// are stored as a pointer to the heap.
+/// ### `MyStructMutex`
+/// Only generated when using `#[self_referencing(sync)]`. It stores an instance of `MyStruct` in a
+/// `std::sync::Mutex` so that it can be shared between threads, E.G. through an `Arc`. Every
+/// accessor listed above which takes a `user` function has a `_locked` version, E.G.
+/// `with_FIELD_locked` or `with_mut_locked`, which takes `&self`, locks the mutex and holds the lock
+/// only while `user` runs. `lock()` returns the guard itself for calling several accessors under a
+/// single lock. Poisoning is ignored since the struct is always left in a valid state.
 pub use ouroboros_macro::self_referencing;
 
 #[doc(hidden)]
 pub mod macro_help {
+    use alloc::alloc::{dealloc, Layout};
+    use alloc::boxed::Box;
+    use core::mem::ManuallyDrop;
+    use core::ops::{Deref, DerefMut};
+    use core::ptr::NonNull;
     use stable_deref_trait::StableDeref;
-    use std::ops::DerefMut;
-
-    /// Converts a reference to an object implementing Deref to a static reference to the data it
-    /// Derefs to. This is obviously unsafe because the compiler can no longer guarantee that the
-    /// data outlives the reference. This function is templated to only work for containers that
-    /// implement StableDeref, E.G. Box and Rc. The intent is that the data that is being pointed
-    /// to will never move as long as the container itself is not dropped. It is up to the consumer
-    /// to get rid of the reference before the container is dropped. The + 'static ensures that
-    /// whatever we are referring to will remain valid indefinitely, that there are no limitations
-    /// on how long the pointer itself can live.
+
+    /// A heap allocation used to store fields which are borrowed as a whole rather than through
+    /// their contents. Unlike `Box`, moving this type does not assert unique ownership of the
+    /// data it points to, so references to that data created by other fields remain valid when
+    /// the struct containing it is moved.
+    pub struct AliasableBox<T>(NonNull<T>);
+
+    impl<T> AliasableBox<T> {
+        pub fn new(value: T) -> Self {
+            Self(NonNull::from(Box::leak(Box::new(value))))
+        }
+
+        pub fn into_inner(this: Self) -> T {
+            let this = ManuallyDrop::new(this);
+            unsafe {
+                let value = core::ptr::read(this.0.as_ptr());
+                Self::deallocate(this.0);
+                value
+            }
+        }
+
+        /// Frees the allocation without touching its contents. This is done without turning it
+        /// back into a Box, which would assert unique access to data that is still borrowed by
+        /// other fields at this point.
+        unsafe fn deallocate(ptr: NonNull<T>) {
+            let layout = Layout::new::<T>();
+            if layout.size() != 0 {
+                dealloc(ptr.as_ptr() as *mut u8, layout);
+            }
+        }
+    }
+
+    impl<T> Deref for AliasableBox<T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            unsafe { self.0.as_ref() }
+        }
+    }
+
+    impl<T> DerefMut for AliasableBox<T> {
+        fn deref_mut(&mut self) -> &mut T {
+            unsafe { self.0.as_mut() }
+        }
+    }
+
+    impl<T> Drop for AliasableBox<T> {
+        fn drop(&mut self) {
+            unsafe {
+                core::ptr::drop_in_place(self.0.as_ptr());
+                Self::deallocate(self.0);
+            }
+        }
+    }
+
+    // AliasableBox owns its contents just like Box does.
+    unsafe impl<T: Send> Send for AliasableBox<T> {}
+    unsafe impl<T: Sync> Sync for AliasableBox<T> {}
+
+    /// Holds the fields of a self-referencing struct. Its contents can only be reached through
+    /// unsafe functions, so that safe code in the module declaring the struct cannot read the
+    /// references between its fields with their erased lifetimes, or move the fields they point
+    /// to. There is no safe way to create, clone or take out a `Hidden`, so the only thing safe
+    /// code can do with one is swap it with the storage of another instance, which moves every
+    /// field together just like moving the struct does.
+    #[repr(transparent)]
+    pub struct Hidden<T>(T);
+
+    impl<T> Hidden<T> {
+        /// # Safety
+        ///
+        /// The caller must ensure that nothing borrowed by other fields is moved or mutated and
+        /// that no reference with an erased lifetime escapes.
+        pub unsafe fn get(this: &Self) -> &T {
+            &this.0
+        }
+
+        /// # Safety
+        ///
+        /// Like `get`.
+        pub unsafe fn get_mut(this: &mut Self) -> &mut T {
+            &mut this.0
+        }
+
+        /// Used while constructing and tearing down the struct. Dereferencing the result has the
+        /// same requirements as `get` and `get_mut`.
+        pub fn as_mut_ptr(this: *mut Self) -> *mut T {
+            this as *mut T
+        }
+    }
+
+    /// Converts a reference to a reference with an arbitrary lifetime. Used for fields which are
+    /// borrowed as a whole, which are stored in an `AliasableBox` so that the data being pointed to
+    /// does not move.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the returned reference is not used after the originally passed
+    /// reference would become invalid.
+    pub unsafe fn strip_lifetime<'a, T: 'a>(data: &T) -> &'a T {
+        &*(data as *const _)
+    }
+
+    /// Like strip_lifetime, but for mutable references.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the returned reference is not used after the originally passed
+    /// reference would become invalid.
+    pub unsafe fn strip_lifetime_mut<'a, T: 'a>(data: &mut T) -> &'a mut T {
+        &mut *(data as *mut _)
+    }
+
+    /// Converts a reference to an object implementing Deref to a reference with an arbitrary
+    /// lifetime to the data it Derefs to. This is obviously unsafe because the compiler can no
+    /// longer guarantee that the data outlives the reference. This function is templated to only
+    /// work for containers that implement StableDeref, E.G. Box and Rc. The intent is that the data
+    /// that is being pointed to will never move as long as the container itself is not dropped. It
+    /// is up to the consumer to get rid of the reference before the container is dropped. The
+    /// `T: 'a` bound ensures that the container's type remains valid for the whole lifetime of the
+    /// returned reference. Generated code uses the first lifetime parameter of the struct, or
+    /// `'static` if there is none.
     /// 
     /// # Safety
     /// 
     /// The caller must ensure that the returned reference is not used after the originally passed
     /// reference would become invalid.
-    pub unsafe fn stable_deref_and_strip_lifetime<T: StableDeref + 'static>(
+    pub unsafe fn stable_deref_and_strip_lifetime<'a, T: StableDeref + 'a>(
         data: &T,
-    ) -> &'static T::Target {
+    ) -> &'a T::Target {
         &*((&**data) as *const _)
     }
 
@@ -213,9 +601,60 @@ pub mod macro_help {
     /// 
     /// The caller must ensure that the returned reference is not used after the originally passed
     /// reference would become invalid.
-    pub unsafe fn stable_deref_and_strip_lifetime_mut<T: StableDeref + DerefMut + 'static>(
+    pub unsafe fn stable_deref_and_strip_lifetime_mut<'a, T: StableDeref + DerefMut + 'a>(
         data: &mut T,
-    ) -> &'static mut T::Target {
+    ) -> &'a mut T::Target {
         &mut *((&mut **data) as *mut _)
     }
+
+    /// Like stable_deref_and_strip_lifetime, but for fields declared as `Option<T>`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the returned reference is not used after the originally passed
+    /// reference would become invalid.
+    pub unsafe fn optional_stable_deref_and_strip_lifetime<'a, T: StableDeref + 'a>(
+        data: &Option<T>,
+    ) -> Option<&'a T::Target> {
+        data.as_ref().map(|data| stable_deref_and_strip_lifetime(data))
+    }
+
+    /// Like optional_stable_deref_and_strip_lifetime, but for mutable references.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the returned reference is not used after the originally passed
+    /// reference would become invalid.
+    pub unsafe fn optional_stable_deref_and_strip_lifetime_mut<
+        'a,
+        T: StableDeref + DerefMut + 'a,
+    >(
+        data: &mut Option<T>,
+    ) -> Option<&'a mut T::Target> {
+        data.as_mut().map(|data| stable_deref_and_strip_lifetime_mut(data))
+    }
+
+    /// Panics when dropped. Used by `replace_FIELD` while some fields of the struct have been
+    /// dropped and not rebuilt yet. If a builder panics, this is dropped while unwinding, and
+    /// panicking again aborts the process instead of letting the half-built struct be dropped.
+    pub struct AbortOnUnwind;
+
+    impl Drop for AbortOnUnwind {
+        fn drop(&mut self) {
+            panic!("A self-referencing struct could not be rebuilt because a builder panicked.");
+        }
+    }
+
+    /// Used by structs which delegate `Stream` to a field.
+    #[cfg(feature = "stream")]
+    pub use futures_core::Stream;
+
+    /// Used by structs which delegate `std::io` traits to a field. Generated code refers to these
+    /// through this module so that it compiles in `no_std` crates which enable the `std` feature.
+    #[cfg(feature = "std")]
+    pub use std::io;
+
+    /// Used by the wrapper generated by `#[self_referencing(sync)]`.
+    #[cfg(feature = "std")]
+    pub use std::sync::{Mutex, MutexGuard, PoisonError};
 }
@@ -0,0 +1,147 @@
+//! Owned versions of the guards returned by `Mutex` and `RwLock`. Each type stores the `Arc`
+//! containing the lock alongside a guard borrowing from it, so that the guard can be returned from
+//! functions or stored in other structs. These require the `std` feature. The contents of the lock
+//! have to be `'static`, since these types have no lifetime parameter for them to outlive instead.
+
+use crate::self_referencing;
+use core::ops::{Deref, DerefMut};
+use std::sync::{
+    Arc, LockResult, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+
+#[self_referencing]
+/// An owned version of [`MutexGuard`], created by [`ArcMutexGuard::lock()`]. The mutex is
+/// unlocked when this is dropped.
+pub struct ArcMutexGuard<T: 'static> {
+    mutex: Arc<Mutex<T>>,
+    #[borrows(mutex)]
+    guard: MutexGuard<'this, T>,
+}
+
+impl<T: 'static> ArcMutexGuard<T> {
+    /// Locks the mutex, blocking the current thread until it is available. Like
+    /// [`Mutex::lock()`], this returns an error containing the guard if the mutex is poisoned.
+    pub fn lock(mutex: Arc<Mutex<T>>) -> LockResult<Self> {
+        let mut poisoned = false;
+        let guard = Self::new(mutex, |mutex| {
+            mutex.lock().unwrap_or_else(|err| {
+                poisoned = true;
+                err.into_inner()
+            })
+        });
+        if poisoned {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// Unlocks the mutex and returns the `Arc` it is stored in.
+    pub fn unlock(this: Self) -> Arc<Mutex<T>> {
+        this.into_heads().mutex
+    }
+}
+
+impl<T: 'static> Deref for ArcMutexGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.with_guard(|guard| &**guard)
+    }
+}
+
+impl<T: 'static> DerefMut for ArcMutexGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.with_guard_mut(|guard| &mut **guard)
+    }
+}
+
+#[self_referencing]
+/// An owned version of [`RwLockReadGuard`], created by [`ArcRwLockReadGuard::read()`]. The shared
+/// lock is released when this is dropped.
+pub struct ArcRwLockReadGuard<T: 'static> {
+    lock: Arc<RwLock<T>>,
+    #[borrows(lock)]
+    guard: RwLockReadGuard<'this, T>,
+}
+
+impl<T: 'static> ArcRwLockReadGuard<T> {
+    /// Locks the `RwLock` with shared read access, blocking the current thread until it is
+    /// available. Like [`RwLock::read()`], this returns an error containing the guard if the lock
+    /// is poisoned.
+    pub fn read(lock: Arc<RwLock<T>>) -> LockResult<Self> {
+        let mut poisoned = false;
+        let guard = Self::new(lock, |lock| {
+            lock.read().unwrap_or_else(|err| {
+                poisoned = true;
+                err.into_inner()
+            })
+        });
+        if poisoned {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// Releases the lock and returns the `Arc` it is stored in.
+    pub fn unlock(this: Self) -> Arc<RwLock<T>> {
+        this.into_heads().lock
+    }
+}
+
+impl<T: 'static> Deref for ArcRwLockReadGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.with_guard(|guard| &**guard)
+    }
+}
+
+#[self_referencing]
+/// An owned version of [`RwLockWriteGuard`], created by [`ArcRwLockWriteGuard::write()`]. The
+/// exclusive lock is released when this is dropped.
+pub struct ArcRwLockWriteGuard<T: 'static> {
+    lock: Arc<RwLock<T>>,
+    #[borrows(lock)]
+    guard: RwLockWriteGuard<'this, T>,
+}
+
+impl<T: 'static> ArcRwLockWriteGuard<T> {
+    /// Locks the `RwLock` with exclusive write access, blocking the current thread until it is
+    /// available. Like [`RwLock::write()`], this returns an error containing the guard if the lock
+    /// is poisoned.
+    pub fn write(lock: Arc<RwLock<T>>) -> LockResult<Self> {
+        let mut poisoned = false;
+        let guard = Self::new(lock, |lock| {
+            lock.write().unwrap_or_else(|err| {
+                poisoned = true;
+                err.into_inner()
+            })
+        });
+        if poisoned {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// Releases the lock and returns the `Arc` it is stored in.
+    pub fn unlock(this: Self) -> Arc<RwLock<T>> {
+        this.into_heads().lock
+    }
+}
+
+impl<T: 'static> Deref for ArcRwLockWriteGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.with_guard(|guard| &**guard)
+    }
+}
+
+impl<T: 'static> DerefMut for ArcRwLockWriteGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.with_guard_mut(|guard| &mut **guard)
+    }
+}
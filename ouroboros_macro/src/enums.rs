@@ -0,0 +1,509 @@
+//! Support for `#[self_referencing]` on enums. Every variant which borrows from its own fields is
+//! turned into a self-referencing struct of its own, and the enum wraps that struct.
+
+use crate::parse::{self, Options};
+use crate::{
+    declaration_order, erased_lifetime, make_constructor_arg_type, make_generic_arguments,
+    retain_generics, self_referencing_impl, tokens_mention, ArgType, Errors,
+};
+use inflector::Inflector;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{Error, Fields, Generics, Ident, ItemEnum, ItemStruct};
+
+/// How a single variant of the original enum is represented after expansion.
+enum VariantKind {
+    /// The variant borrows from its own fields and is stored as a generated self-referencing
+    /// struct with the given name, which only takes the generic arguments its fields use.
+    SelfReferencing {
+        struct_name: Ident,
+        generic_args: Vec<TokenStream2>,
+    },
+    /// The variant has no `#[borrows]` attributes and is kept exactly as it was written.
+    Plain(Fields),
+}
+
+struct VariantInfo {
+    name: Ident,
+    kind: VariantKind,
+}
+
+fn has_borrows(fields: &Fields) -> bool {
+    fields.iter().any(|field| {
+        field
+            .attrs
+            .iter()
+            .any(|attr| parse::attr_is(attr, "borrows"))
+    })
+}
+
+fn make_documentation(options: &Options, documentation: String) -> TokenStream2 {
+    if !options.do_no_doc {
+        quote! { #[doc=#documentation] }
+    } else {
+        quote! { #[doc(hidden)] }
+    }
+}
+
+/// Returns the generic parameters of the enum which are used by the fields of a variant, since
+/// the struct generated for it cannot have parameters it does not use. The lifetime returned by
+/// erased_lifetime counts as used if the fields use the internal lifetime, since that is what it
+/// is replaced with.
+fn variant_generics(fields: &Fields, generics: &Generics, internal_lifetime: &Ident) -> Generics {
+    let field_types = fields.iter().map(|field| &field.ty);
+    let field_types = quote! { #(#field_types)* };
+    let erased = erased_lifetime(generics);
+    retain_generics(generics, |ident| {
+        tokens_mention(field_types.clone(), ident)
+            || (ident == &erased && tokens_mention(field_types.clone(), internal_lifetime))
+    })
+}
+
+/// Returns a pattern which binds every field of a plain variant, which can also be used as an
+/// expression to build a variant with the same shape.
+fn make_plain_pattern(fields: &Fields) -> TokenStream2 {
+    match fields {
+        Fields::Named(fields) => {
+            let names = fields
+                .named
+                .iter()
+                .map(|field| field.ident.clone().expect("Named field has no name."));
+            quote! { { #(#names),* } }
+        }
+        Fields::Unnamed(fields) => {
+            let names = (0..fields.unnamed.len()).map(|index| format_ident!("field_{}", index));
+            quote! { ( #(#names),* ) }
+        }
+        Fields::Unit => quote! {},
+    }
+}
+
+/// Returns the fields of a plain variant with every type replaced by a reference to that type.
+fn make_plain_ref_fields(fields: &Fields, mutability: TokenStream2) -> TokenStream2 {
+    match fields {
+        Fields::Named(fields) => {
+            let fields = fields.named.iter().map(|field| {
+                let name = &field.ident;
+                let typ = &field.ty;
+                quote! { #name: &'outer_borrow #mutability #typ }
+            });
+            quote! { { #(#fields),* } }
+        }
+        Fields::Unnamed(fields) => {
+            let fields = fields.unnamed.iter().map(|field| {
+                let typ = &field.ty;
+                quote! { &'outer_borrow #mutability #typ }
+            });
+            quote! { ( #(#fields),* ) }
+        }
+        Fields::Unit => quote! {},
+    }
+}
+
+pub fn self_referencing_enum_impl(
+    original_enum_def: ItemEnum,
+    options: &Options,
+) -> Result<TokenStream2, Error> {
+    if options.sync {
+        return Err(Error::new(
+            Span::call_site(),
+            "The `sync` option is not supported on enums yet.",
+        ));
+    }
+    if let Some(on_drop) = &options.on_drop {
+        return Err(Error::new_spanned(
+            on_drop,
+            "The `on_drop` option is not supported on enums yet.",
+        ));
+    }
+    let enum_name = &original_enum_def.ident;
+    let visibility = &original_enum_def.vis;
+    let generic_params = original_enum_def.generics.clone();
+    let generic_args = make_generic_arguments(&generic_params);
+    let internal_lifetime = options
+        .lifetime
+        .as_ref()
+        .map_or_else(|| format_ident!("this"), |lifetime| lifetime.ident.clone());
+
+    let mut actual_enum_def = original_enum_def.clone();
+    let mut variant_structs = Vec::new();
+    let mut variants = Vec::new();
+    let mut constructors = Vec::new();
+    // Every variant is checked before reporting anything, so that all mistakes show up at once.
+    let mut errors = Errors::default();
+    for variant in &mut actual_enum_def.variants {
+        if !has_borrows(&variant.fields) {
+            variants.push(VariantInfo {
+                name: variant.ident.clone(),
+                kind: VariantKind::Plain(variant.fields.clone()),
+            });
+            continue;
+        }
+        if let Fields::Unnamed(_) = &variant.fields {
+            errors.push(Error::new_spanned(
+                &variant.ident,
+                "Tuple variants are not supported yet, use named fields instead.",
+            ));
+            continue;
+        }
+        let struct_name = format_ident!("{}{}", enum_name, variant.ident);
+        let struct_documentation = format!(
+            "The contents of the [`{0}::{1}`]({0}::{1}) variant of [`{0}`]({0}).",
+            enum_name, variant.ident
+        );
+        // The enum's derives need the same traits to be implemented for the variant's struct.
+        let derives = original_enum_def
+            .attrs
+            .iter()
+            .filter(|attr| parse::attr_is(attr, "derive"))
+            .cloned();
+        let struct_def = ItemStruct {
+            attrs: std::iter::once(syn::parse_quote! { #[doc=#struct_documentation] })
+                .chain(derives)
+                .collect(),
+            vis: original_enum_def.vis.clone(),
+            struct_token: Default::default(),
+            ident: struct_name.clone(),
+            generics: variant_generics(&variant.fields, &generic_params, &internal_lifetime),
+            fields: variant.fields.clone(),
+            semi_token: None,
+        };
+        // The constructor of the variant is built from the same field information as the
+        // generated struct, after the internal lifetime has been renamed to 'this.
+        let struct_generic_args = make_generic_arguments(&struct_def.generics);
+        let field_info = match self_referencing_impl(struct_def, options) {
+            Ok((variant_struct, field_info)) => {
+                variant_structs.push(variant_struct);
+                field_info
+            }
+            Err(err) => {
+                errors.push(err);
+                continue;
+            }
+        };
+
+        let variant_name = &variant.ident;
+        let mut params = Vec::new();
+        let mut args = Vec::new();
+        for index in declaration_order(&field_info) {
+            let field = &field_info[index];
+            match make_constructor_arg_type(field, &field_info) {
+                ArgType::Plain(plain_type) => {
+                    let field_name = &field.name;
+                    params.push(quote! { #field_name: #plain_type });
+                    args.push(field_name.clone());
+                }
+                ArgType::TraitBound(bound_type) => {
+                    let builder_name = field.builder_name();
+                    params.push(quote! { #builder_name: impl #bound_type });
+                    args.push(builder_name);
+                }
+            }
+        }
+        let constructor_name = format_ident!("new_{}", variant_name.to_string().to_snake_case());
+        let documentation = make_documentation(
+            options,
+            format!(
+                concat!(
+                    "Constructs the [`{0}`](Self::{0}) variant. The arguments are the same as ",
+                    "the ones taken by [`{1}::new()`]({1}::new)."
+                ),
+                variant_name, struct_name
+            ),
+        );
+        constructors.push(quote! {
+            #documentation
+            pub fn #constructor_name(#(#params),*) -> Self {
+                Self::#variant_name(#struct_name::new(#(#args),*))
+            }
+        });
+
+        variant.fields = Fields::Unnamed(syn::parse_quote! {
+            (#struct_name <#(#struct_generic_args),*>)
+        });
+        variants.push(VariantInfo {
+            name: variant.ident.clone(),
+            kind: VariantKind::SelfReferencing {
+                struct_name,
+                generic_args: struct_generic_args,
+            },
+        });
+    }
+    errors.finish()?;
+    if variant_structs.is_empty() {
+        return Err(Error::new(
+            Span::call_site(),
+            concat!(
+                "Self-referencing enums must have at least one variant which borrows from its ",
+                "own fields, try adding #[borrows(...)] to a field of a variant."
+            ),
+        ));
+    }
+
+    let heads_name = format_ident!("{}Heads", enum_name);
+    let borrowed_name = format_ident!("{}Borrowed", enum_name);
+    let borrowed_mut_name = format_ident!("{}BorrowedMut", enum_name);
+    let borrowed_generic_params = if generic_params.params.is_empty() {
+        quote! { <'outer_borrow, 'this> }
+    } else {
+        let mut new_generic_params = generic_params.clone();
+        new_generic_params
+            .params
+            .insert(0, syn::parse_quote! { 'this });
+        new_generic_params
+            .params
+            .insert(0, syn::parse_quote! { 'outer_borrow });
+        quote! { #new_generic_params }
+    };
+    let borrowed_generic_args = {
+        let mut args = generic_args.clone();
+        args.insert(0, quote! { 'this });
+        args.insert(0, quote! { 'outer_borrow });
+        args
+    };
+
+    let mut heads_variants = Vec::new();
+    let mut borrowed_variants = Vec::new();
+    let mut borrowed_mut_variants = Vec::new();
+    let mut into_heads_arms = Vec::new();
+    let mut match_with_arms = Vec::new();
+    let mut match_with_mut_arms = Vec::new();
+    let mut variant_users = Vec::new();
+    for variant in &variants {
+        let variant_name = &variant.name;
+        match &variant.kind {
+            VariantKind::SelfReferencing {
+                struct_name,
+                generic_args: variant_generic_args,
+            } => {
+                let variant_borrowed_generic_args = {
+                    let mut args = variant_generic_args.clone();
+                    args.insert(0, quote! { 'this });
+                    args.insert(0, quote! { 'outer_borrow });
+                    args
+                };
+                let variant_heads_name = crate::heads_name(struct_name);
+                let variant_borrowed_name = crate::borrowed_fields_name(struct_name);
+                let variant_borrowed_mut_name = crate::borrowed_mut_fields_name(struct_name);
+                heads_variants.push(quote! {
+                    #variant_name(#variant_heads_name<#(#variant_generic_args),*>)
+                });
+                borrowed_variants.push(quote! {
+                    #variant_name(#variant_borrowed_name<#(#variant_borrowed_generic_args),*>)
+                });
+                borrowed_mut_variants.push(quote! {
+                    #variant_name(#variant_borrowed_mut_name<#(#variant_borrowed_generic_args),*>)
+                });
+                into_heads_arms.push(quote! {
+                    Self::#variant_name(variant) => #heads_name::#variant_name(variant.into_heads())
+                });
+                match_with_arms.push(quote! {
+                    Self::#variant_name(variant) => {
+                        variant.with(|fields| user(#borrowed_name::#variant_name(fields)))
+                    }
+                });
+                match_with_mut_arms.push(quote! {
+                    Self::#variant_name(variant) => {
+                        variant.with_mut(|fields| user(#borrowed_mut_name::#variant_name(fields)))
+                    }
+                });
+
+                let snake_name = variant_name.to_string().to_snake_case();
+                let with_name = format_ident!("with_{}", snake_name);
+                let with_mut_name = format_ident!("with_{}_mut", snake_name);
+                let documentation = make_documentation(
+                    options,
+                    format!(
+                        concat!(
+                            "If this is the [`{0}`](Self::{0}) variant, calls the provided ",
+                            "function with immutable references to its fields as described by ",
+                            "[`{1}::with()`]({1}::with). Returns `None` for any other variant."
+                        ),
+                        variant_name, struct_name
+                    ),
+                );
+                let mut_documentation = make_documentation(
+                    options,
+                    format!(
+                        concat!(
+                            "If this is the [`{0}`](Self::{0}) variant, calls the provided ",
+                            "function with mutable references to its fields as described by ",
+                            "[`{1}::with_mut()`]({1}::with_mut). Returns `None` for any other ",
+                            "variant."
+                        ),
+                        variant_name, struct_name
+                    ),
+                );
+                variant_users.push(quote! {
+                    #documentation
+                    #[allow(unreachable_patterns)]
+                    pub fn #with_name <'outer_borrow, ReturnType>(
+                        &'outer_borrow self,
+                        user: impl for<'this> ::core::ops::FnOnce(
+                            #variant_borrowed_name<#(#variant_borrowed_generic_args),*>
+                        ) -> ReturnType
+                    ) -> ::core::option::Option<ReturnType> {
+                        match self {
+                            Self::#variant_name(variant) => {
+                                ::core::option::Option::Some(variant.with(user))
+                            }
+                            _ => ::core::option::Option::None,
+                        }
+                    }
+                    #mut_documentation
+                    #[allow(unreachable_patterns)]
+                    pub fn #with_mut_name <'outer_borrow, ReturnType>(
+                        &'outer_borrow mut self,
+                        user: impl for<'this> ::core::ops::FnOnce(
+                            #variant_borrowed_mut_name<#(#variant_borrowed_generic_args),*>
+                        ) -> ReturnType
+                    ) -> ::core::option::Option<ReturnType> {
+                        match self {
+                            Self::#variant_name(variant) => {
+                                ::core::option::Option::Some(variant.with_mut(user))
+                            }
+                            _ => ::core::option::Option::None,
+                        }
+                    }
+                });
+            }
+            VariantKind::Plain(fields) => {
+                heads_variants.push(quote! { #variant_name #fields });
+                let ref_fields = make_plain_ref_fields(fields, quote! {});
+                borrowed_variants.push(quote! { #variant_name #ref_fields });
+                let mut_ref_fields = make_plain_ref_fields(fields, quote! { mut });
+                borrowed_mut_variants.push(quote! { #variant_name #mut_ref_fields });
+                let pattern = make_plain_pattern(fields);
+                into_heads_arms.push(quote! {
+                    Self::#variant_name #pattern => #heads_name::#variant_name #pattern
+                });
+                match_with_arms.push(quote! {
+                    Self::#variant_name #pattern => user(#borrowed_name::#variant_name #pattern)
+                });
+                match_with_mut_arms.push(quote! {
+                    Self::#variant_name #pattern => user(#borrowed_mut_name::#variant_name #pattern)
+                });
+            }
+        }
+    }
+
+    let heads_documentation = format!(
+        concat!(
+            "An enum which contains only the ",
+            "[head fields](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions) ",
+            "of each variant of [`{0}`]({0})."
+        ),
+        enum_name
+    );
+    let borrowed_documentation = format!(
+        concat!(
+            "An enum holding immutable references to the fields of whichever variant of ",
+            "[`{0}`]({0}) is active, as passed to [`{0}::match_with()`]({0}::match_with)."
+        ),
+        enum_name
+    );
+    let borrowed_mut_documentation = format!(
+        concat!(
+            "An enum holding mutable references to the fields of whichever variant of ",
+            "[`{0}`]({0}) is active, as passed to [`{0}::match_with_mut()`]({0}::match_with_mut)."
+        ),
+        enum_name
+    );
+    let into_heads_documentation = make_documentation(
+        options,
+        concat!(
+            "This function drops all internally referencing fields of the active variant and ",
+            "returns only its [head fields](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions)."
+        )
+        .to_owned(),
+    );
+    let match_with_documentation = make_documentation(
+        options,
+        concat!(
+            "Calls the provided function with immutable references to the fields of the active ",
+            "variant. Self-referencing variants provide the same references as their `with()` ",
+            "method."
+        )
+        .to_owned(),
+    );
+    let match_with_mut_documentation = make_documentation(
+        options,
+        concat!(
+            "Calls the provided function with mutable references to the fields of the active ",
+            "variant. Self-referencing variants provide the same references as their ",
+            "`with_mut()` method."
+        )
+        .to_owned(),
+    );
+
+    let from_impls = variants.iter().filter_map(|variant| {
+        let variant_name = &variant.name;
+        if let VariantKind::SelfReferencing {
+            struct_name,
+            generic_args: variant_generic_args,
+        } = &variant.kind
+        {
+            Some(quote! {
+                impl #generic_params ::core::convert::From<#struct_name <#(#variant_generic_args),*>>
+                    for #enum_name <#(#generic_args),*>
+                {
+                    fn from(variant: #struct_name <#(#variant_generic_args),*>) -> Self {
+                        Self::#variant_name(variant)
+                    }
+                }
+            })
+        } else {
+            None
+        }
+    });
+
+    Ok(quote! {
+        #(#variant_structs)*
+        #actual_enum_def
+        #[doc=#heads_documentation]
+        #visibility enum #heads_name #generic_params {
+            #(#heads_variants),*
+        }
+        #[doc=#borrowed_documentation]
+        #visibility enum #borrowed_name #borrowed_generic_params {
+            #(#borrowed_variants),*
+        }
+        #[doc=#borrowed_mut_documentation]
+        #visibility enum #borrowed_mut_name #borrowed_generic_params {
+            #(#borrowed_mut_variants),*
+        }
+        #(#from_impls)*
+        impl #generic_params #enum_name <#(#generic_args),*> {
+            #(#constructors)*
+            #(#variant_users)*
+            #match_with_documentation
+            pub fn match_with <'outer_borrow, ReturnType>(
+                &'outer_borrow self,
+                user: impl for<'this> ::core::ops::FnOnce(
+                    #borrowed_name<#(#borrowed_generic_args),*>
+                ) -> ReturnType
+            ) -> ReturnType {
+                match self {
+                    #(#match_with_arms),*
+                }
+            }
+            #match_with_mut_documentation
+            pub fn match_with_mut <'outer_borrow, ReturnType>(
+                &'outer_borrow mut self,
+                user: impl for<'this> ::core::ops::FnOnce(
+                    #borrowed_mut_name<#(#borrowed_generic_args),*>
+                ) -> ReturnType
+            ) -> ReturnType {
+                match self {
+                    #(#match_with_mut_arms),*
+                }
+            }
+            #into_heads_documentation
+            pub fn into_heads(self) -> #heads_name <#(#generic_args),*> {
+                match self {
+                    #(#into_heads_arms),*
+                }
+            }
+        }
+    })
+}
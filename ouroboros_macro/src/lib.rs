@@ -1,10 +1,17 @@
+mod enums;
+mod parse;
+
 use inflector::Inflector;
+use parse::{BorrowItem, DelegatedTrait, FieldOptions, Options};
 use proc_macro::TokenStream;
 use proc_macro2::TokenStream as TokenStream2;
-use proc_macro2::{Group, Span, TokenTree};
-use quote::{format_ident, quote};
+use proc_macro2::{Span, TokenTree};
+use quote::{format_ident, quote, quote_spanned};
+use syn::visit::Visit;
+use syn::visit_mut::VisitMut;
 use syn::{
-    Attribute, Error, Fields, GenericParam, Generics, Ident, ItemStruct, PathArguments, Type,
+    Error, Fields, GenericArgument, GenericParam, Generics, Ident, Item, ItemStruct, Lifetime,
+    PathArguments, Type,
 };
 
 #[derive(Clone, Copy, PartialEq)]
@@ -26,13 +33,29 @@ impl FieldType {
 struct BorrowRequest {
     index: usize,
     mutable: bool,
+    /// True if the field itself is borrowed rather than the data it derefs to.
+    container: bool,
 }
 
 struct StructFieldInfo {
     name: Ident,
     typ: Type,
+    /// The position of the field in the original struct. Fields are kept in the order they are
+    /// constructed in, but everything which takes one argument per field follows the order they
+    /// were declared in.
+    declaration_index: usize,
     field_type: FieldType,
     borrows: Vec<BorrowRequest>,
+    /// True if at least one other field borrows the contents of this field.
+    contents_borrowed: bool,
+    /// True if at least one other field borrows this field itself. Such fields are stored in an
+    /// `AliasableBox` so that the container does not move when the struct does.
+    container_borrowed: bool,
+    /// If the field was declared as `Option<T>`, this is `T`. The contents of such fields are
+    /// borrowed as an `Option<&T::Target>`.
+    option_inner: Option<Type>,
+    /// Options taken from `#[ouroboros(...)]` attributes on the field.
+    options: FieldOptions,
 }
 
 impl StructFieldInfo {
@@ -44,6 +67,119 @@ impl StructFieldInfo {
         format_ident!("{}_illegal_static_reference", self.name)
     }
 
+    fn illegal_container_ref_name(&self) -> Ident {
+        format_ident!("{}_illegal_static_container_reference", self.name)
+    }
+
+    /// Returns the name of the variable created by make_illegal_static_reference which should be
+    /// passed to the builder of a field making the given borrow.
+    fn illegal_ref_name_for(&self, borrow: &BorrowRequest) -> Ident {
+        if borrow.container {
+            self.illegal_container_ref_name()
+        } else {
+            self.illegal_ref_name()
+        }
+    }
+
+    /// Returns the names of the references which should be passed to this field's builder.
+    fn builder_args(&self, field_info: &[StructFieldInfo]) -> Vec<Ident> {
+        self.borrows
+            .iter()
+            .map(|borrow| field_info[borrow.index].illegal_ref_name_for(borrow))
+            .collect()
+    }
+
+    /// The type this field has in the storage struct. Every field is wrapped in a `ManuallyDrop`
+    /// so that the struct can drop them in dependency order, and fields which are borrowed as a
+    /// whole are boxed as well. `ManuallyDrop` is also allowed to hold dangling references, which
+    /// into_heads relies on when it frees a boxed head while `self` still refers to it.
+    fn stored_type(&self) -> Type {
+        let field_type = &self.typ;
+        if self.container_borrowed {
+            syn::parse_quote! {
+                ::core::mem::ManuallyDrop<::ouroboros::macro_help::AliasableBox<#field_type>>
+            }
+        } else {
+            syn::parse_quote! { ::core::mem::ManuallyDrop<#field_type> }
+        }
+    }
+
+    /// Converts an expression of the declared type of this field to an expression of its
+    /// stored type.
+    fn make_stored_value(&self, value: TokenStream2) -> TokenStream2 {
+        if self.container_borrowed {
+            quote! {
+                ::core::mem::ManuallyDrop::new(::ouroboros::macro_help::AliasableBox::new(#value))
+            }
+        } else {
+            quote! { ::core::mem::ManuallyDrop::new(#value) }
+        }
+    }
+
+    /// Converts an expression of the stored type of this field back to its declared type.
+    fn make_unstored_value(&self, value: TokenStream2) -> TokenStream2 {
+        if self.container_borrowed {
+            quote! {
+                ::ouroboros::macro_help::AliasableBox::into_inner(
+                    ::core::mem::ManuallyDrop::into_inner(#value)
+                )
+            }
+        } else {
+            quote! { ::core::mem::ManuallyDrop::into_inner(#value) }
+        }
+    }
+
+    /// Returns a place expression for the value of this field in `base`, looking through the
+    /// `ManuallyDrop` and `AliasableBox` it is stored in.
+    fn make_field_place(&self, base: TokenStream2) -> TokenStream2 {
+        let field_name = &self.name;
+        if self.container_borrowed {
+            quote! { (**#base.#field_name) }
+        } else {
+            quote! { (*#base.#field_name) }
+        }
+    }
+
+    /// Returns the type of a reference to the contents of this field, which is wrapped in an
+    /// `Option` if the field itself is optional.
+    fn contents_ref_type(&self, lifetime: TokenStream2, mutable: bool) -> TokenStream2 {
+        let mutability = if mutable {
+            quote! { mut }
+        } else {
+            quote! {}
+        };
+        if let Some(inner) = &self.option_inner {
+            let content_type = deref_type(inner);
+            quote! { ::core::option::Option<&#lifetime #mutability #content_type> }
+        } else {
+            let content_type = deref_type(&self.typ);
+            quote! { &#lifetime #mutability #content_type }
+        }
+    }
+
+    /// Returns an expression borrowing this field itself from the `storage` bound by
+    /// bind_storage.
+    fn make_field_ref(&self) -> TokenStream2 {
+        let place = self.make_field_place(quote! { storage });
+        quote! { &#place }
+    }
+
+    /// Returns an expression mutably borrowing this field itself from `storage`.
+    fn make_field_mut_ref(&self) -> TokenStream2 {
+        let place = self.make_field_place(quote! { storage });
+        quote! { &mut #place }
+    }
+
+    /// Returns an expression borrowing the contents of this field from `storage`.
+    fn make_contents_ref(&self) -> TokenStream2 {
+        let place = self.make_field_place(quote! { storage });
+        if self.option_inner.is_some() {
+            quote! { #place.as_deref() }
+        } else {
+            quote! { &*#place }
+        }
+    }
+
     // Returns code which takes a variable with the same name and type as this field and turns it
     // into a static reference to its dereffed contents. For example, suppose a field
     // `test: Box<i32>`. This method would generate code that looks like:
@@ -51,32 +187,66 @@ impl StructFieldInfo {
     // // Variable name taken from self.illegal_ref_name()
     // let test_illegal_static_reference = unsafe {
     //     ::ouroboros::macro_help::stable_deref_and_strip_lifetime(
-    //         &((*result.as_ptr()).field)
+    //         &((*storage).field)
     //     )
     // };
     // ```
+    // If the field itself is borrowed, a reference to the boxed container is created as well and
+    // the reference to the contents is derived from it.
     fn make_illegal_static_reference(&self) -> TokenStream2 {
-        let field_name = &self.name;
+        let place = self.make_field_place(quote! { (*storage) });
         let ref_name = self.illegal_ref_name();
-        quote! {
-            let #ref_name = unsafe {
-                ::ouroboros::macro_help::stable_deref_and_strip_lifetime(
-                    &((*result.as_ptr()).#field_name)
-                )
+        if self.container_borrowed {
+            let container_ref_name = self.illegal_container_ref_name();
+            let contents_ref = if !self.contents_borrowed {
+                quote! {}
+            } else if self.option_inner.is_some() {
+                quote! { let #ref_name = #container_ref_name.as_deref(); }
+            } else {
+                quote! { let #ref_name = &**#container_ref_name; }
+            };
+            quote! {
+                let #container_ref_name = unsafe {
+                    ::ouroboros::macro_help::strip_lifetime(&#place)
+                };
+                #contents_ref
+            }
+        } else {
+            let strip_fn = if self.option_inner.is_some() {
+                quote! { optional_stable_deref_and_strip_lifetime }
+            } else {
+                quote! { stable_deref_and_strip_lifetime }
             };
+            quote! {
+                let #ref_name = unsafe {
+                    ::ouroboros::macro_help::#strip_fn(&#place)
+                };
+            }
         }
     }
 
     /// Like make_illegal_static_reference, but provides a mutable reference instead.
     fn make_illegal_static_mut_reference(&self) -> TokenStream2 {
-        let field_name = &self.name;
-        let ref_name = self.illegal_ref_name();
-        quote! {
-            let #ref_name = unsafe {
-                ::ouroboros::macro_help::stable_deref_and_strip_lifetime_mut(
-                    &mut ((*result.as_mut_ptr()).#field_name)
-                )
+        let place = self.make_field_place(quote! { (*storage) });
+        if self.container_borrowed {
+            let container_ref_name = self.illegal_container_ref_name();
+            quote! {
+                let #container_ref_name = unsafe {
+                    ::ouroboros::macro_help::strip_lifetime_mut(&mut #place)
+                };
+            }
+        } else {
+            let ref_name = self.illegal_ref_name();
+            let strip_fn = if self.option_inner.is_some() {
+                quote! { optional_stable_deref_and_strip_lifetime_mut }
+            } else {
+                quote! { stable_deref_and_strip_lifetime_mut }
             };
+            quote! {
+                let #ref_name = unsafe {
+                    ::ouroboros::macro_help::#strip_fn(&mut #place)
+                };
+            }
         }
     }
 }
@@ -89,238 +259,591 @@ enum ArgType {
     TraitBound(TokenStream2),
 }
 
-fn deref_type(field_type: &Type, do_chain_hack: bool) -> Result<TokenStream2, Error> {
-    if do_chain_hack {
-        if let Type::Path(tpath) = field_type {
-            if let Some(segment) = tpath.path.segments.last() {
-                if segment.ident == "Box" {
-                    if let PathArguments::AngleBracketed(args) = &segment.arguments {
-                        if let Some(arg) = args.args.first() {
-                            return Ok(quote! { #arg });
-                        }
-                    }
-                }
-            }
+/// Returns the type a reference to the contents of a borrowed field points to. This is written
+/// as a projection so that any `StableDeref` container works, including type aliases and
+/// containers whose target depends on `'this`.
+fn deref_type(field_type: &Type) -> TokenStream2 {
+    quote! { <#field_type as ::core::ops::Deref>::Target }
+}
+
+/// If the type is written as `Option<T>`, returns `T`. Borrowed fields of such types provide
+/// optional references to their contents instead of requiring the field to implement
+/// `StableDeref`.
+fn optional_inner_type(field_type: &Type) -> Option<&Type> {
+    let path = if let Type::Path(type_path) = field_type {
+        if type_path.qself.is_some() {
+            return None;
         }
-        Err(Error::new_spanned(
-            &field_type,
-            concat!(
-                "Borrowed fields must be of type Box<T> when chain_hack is used. Either change ",
-                "the field to a Box<T> or remove chain_hack."
-            ),
-        ))
+        &type_path.path
     } else {
-        Ok(quote! { <#field_type as ::core::ops::Deref>::Target })
+        return None;
+    };
+    let names: Vec<_> = path
+        .segments
+        .iter()
+        .map(|segment| segment.ident.to_string())
+        .collect();
+    let is_option = match &names[..] {
+        [option] => path.leading_colon.is_none() && option == "Option",
+        [krate, module, option] => {
+            (krate == "std" || krate == "core") && module == "option" && option == "Option"
+        }
+        _ => false,
+    };
+    if !is_option {
+        return None;
+    }
+    if let PathArguments::AngleBracketed(args) = &path.segments.last()?.arguments {
+        if let (1, Some(GenericArgument::Type(inner))) = (args.args.len(), args.args.first()) {
+            return Some(inner);
+        }
     }
+    None
 }
 
 fn make_constructor_arg_type_impl(
     for_field: &StructFieldInfo,
     other_fields: &[StructFieldInfo],
     make_builder_return_type: impl FnOnce() -> TokenStream2,
-    do_chain_hack: bool,
-) -> Result<ArgType, Error> {
+) -> ArgType {
     let field_type = &for_field.typ;
     if for_field.borrows.is_empty() {
-        Ok(ArgType::Plain(quote! { #field_type }))
+        ArgType::Plain(quote! { #field_type })
     } else {
         let mut field_builder_params = Vec::new();
         for borrow in &for_field.borrows {
-            if borrow.mutable {
-                let field = &other_fields[borrow.index];
-                let field_type = &field.typ;
-                let content_type = deref_type(field_type, do_chain_hack)?;
-                field_builder_params.push(quote! {
-                    &'this mut #content_type
-                });
+            let field = &other_fields[borrow.index];
+            let field_type = &field.typ;
+            if borrow.container {
+                if borrow.mutable {
+                    field_builder_params.push(quote! { &'this mut #field_type });
+                } else {
+                    field_builder_params.push(quote! { &'this #field_type });
+                }
             } else {
-                let field = &other_fields[borrow.index];
-                let field_type = &field.typ;
-                let content_type = deref_type(field_type, do_chain_hack)?;
-                field_builder_params.push(quote! {
-                    &'this #content_type
-                });
+                field_builder_params
+                    .push(field.contents_ref_type(quote! { 'this }, borrow.mutable));
             }
         }
         let return_type = make_builder_return_type();
         let bound =
             quote! { for<'this> ::core::ops::FnOnce(#(#field_builder_params),*) -> #return_type };
-        Ok(ArgType::TraitBound(bound))
+        ArgType::TraitBound(bound)
     }
 }
 
+/// Lists the arguments of a field's builder for the documentation tables of the constructors.
+fn make_builder_doc_args(for_field: &StructFieldInfo, other_fields: &[StructFieldInfo]) -> String {
+    let args: Vec<_> = for_field
+        .borrows
+        .iter()
+        .map(|borrow| {
+            format!(
+                "{}: &{}_",
+                other_fields[borrow.index].name,
+                if borrow.mutable { "mut " } else { "" },
+            )
+        })
+        .collect();
+    args.join(", ")
+}
+
 /// Returns a trait bound if `for_field` refers to any other fields, and a plain type if not. This
 /// is the type used in the constructor to initialize the value of `for_field`.
 fn make_constructor_arg_type(
     for_field: &StructFieldInfo,
     other_fields: &[StructFieldInfo],
-    do_chain_hack: bool,
-) -> Result<ArgType, Error> {
+) -> ArgType {
     let field_type = &for_field.typ;
-    make_constructor_arg_type_impl(
-        for_field,
-        other_fields,
-        || quote! { #field_type },
-        do_chain_hack,
-    )
+    make_constructor_arg_type_impl(for_field, other_fields, || quote! { #field_type })
 }
 
 /// Like make_constructor_arg_type, but used for the try_new constructor.
 fn make_try_constructor_arg_type(
     for_field: &StructFieldInfo,
     other_fields: &[StructFieldInfo],
-    do_chain_hack: bool,
-) -> Result<ArgType, Error> {
+) -> ArgType {
     let field_type = &for_field.typ;
     make_constructor_arg_type_impl(
         for_field,
         other_fields,
         || quote! { ::core::result::Result<#field_type, Error_> },
-        do_chain_hack,
     )
 }
 
-fn replace_this_with_static(input: TokenStream2) -> TokenStream2 {
-    input
-        .into_iter()
-        .map(|token| match &token {
-            TokenTree::Ident(ident) => {
-                if ident == "this" {
-                    TokenTree::Ident(format_ident!("static"))
-                } else {
-                    token
+/// Returns the lifetime `'this` is replaced with in the actual struct. This is the lifetime
+/// parameter which the bounds of the struct declare every other one to outlive, or the first one
+/// if the bounds do not single one out, or `'static` if the struct has none. This way heads only
+/// have to outlive the lifetimes the struct is generic over rather than `'static`, and no bounds
+/// between the struct's own lifetimes are assumed. The replacement is never exposed to users of
+/// the struct, who only ever see `'this` as a higher-ranked lifetime.
+fn erased_lifetime(generics: &Generics) -> Ident {
+    let lifetimes: Vec<_> = generics.lifetimes().map(|param| &param.lifetime).collect();
+    lifetimes
+        .iter()
+        .find(|&&shortest| {
+            lifetimes
+                .iter()
+                .all(|lifetime| lifetime_outlives(generics, lifetime, shortest))
+        })
+        .or_else(|| lifetimes.first())
+        .map_or_else(
+            || format_ident!("static"),
+            |lifetime| lifetime.ident.clone(),
+        )
+}
+
+/// Returns true if the bounds of the struct declare that `longer` outlives `shorter`, either
+/// directly or through other lifetimes. Every lifetime outlives itself.
+fn lifetime_outlives(generics: &Generics, longer: &Lifetime, shorter: &Lifetime) -> bool {
+    let mut declared = Vec::new();
+    for param in generics.lifetimes() {
+        for bound in &param.bounds {
+            declared.push((&param.lifetime, bound));
+        }
+    }
+    if let Some(where_clause) = &generics.where_clause {
+        for predicate in &where_clause.predicates {
+            if let syn::WherePredicate::Lifetime(predicate) = predicate {
+                for bound in &predicate.bounds {
+                    declared.push((&predicate.lifetime, bound));
                 }
             }
-            TokenTree::Group(group) => TokenTree::Group(Group::new(
-                group.delimiter(),
-                replace_this_with_static(group.stream()),
-            )),
-            _ => token,
-        })
+        }
+    }
+    let mut outlived = vec![longer];
+    let mut index = 0;
+    while let Some(&current) = outlived.get(index) {
+        if current == shorter {
+            return true;
+        }
+        for &(lifetime, bound) in &declared {
+            if lifetime == current && !outlived.contains(&bound) {
+                outlived.push(bound);
+            }
+        }
+        index += 1;
+    }
+    false
+}
+
+/// Returns bounds stating that every lifetime of the struct which is known to outlive the
+/// lifetime returned by erased_lifetime outlives `'this` as well, for items which name `'this`
+/// as a parameter. Code receiving such items can then rely on `'this` being shorter than the
+/// lifetimes of the struct.
+fn this_outlives_bounds(generics: &Generics) -> Vec<TokenStream2> {
+    let erased = match generics
+        .lifetimes()
+        .find(|param| param.lifetime.ident == erased_lifetime(generics))
+    {
+        Some(param) => &param.lifetime,
+        None => return Vec::new(),
+    };
+    generics
+        .lifetimes()
+        .map(|param| &param.lifetime)
+        .filter(|lifetime| lifetime_outlives(generics, lifetime, erased))
+        .map(|lifetime| quote! { #lifetime: 'this })
         .collect()
 }
 
+/// Renames every use of one lifetime. Only lifetimes are visited, so paths, identifiers and macro
+/// arguments which happen to be spelled the same as the lifetime are left alone.
+struct RenameLifetime<'a> {
+    from: &'a Ident,
+    to: &'a Ident,
+}
+
+impl VisitMut for RenameLifetime<'_> {
+    fn visit_lifetime_mut(&mut self, lifetime: &mut Lifetime) {
+        if lifetime.ident == *self.from {
+            lifetime.ident = Ident::new(&self.to.to_string(), lifetime.ident.span());
+        }
+    }
+}
+
+/// Renames the internal lifetime chosen with `#[self_referencing(lifetime = 'x)]` to `'this` in
+/// the types of all fields, so that the rest of the macro only has to deal with `'this`.
+fn rename_internal_lifetime(struct_def: &mut ItemStruct, lifetime: &Lifetime) -> Result<(), Error> {
+    if lifetime.ident == "this" {
+        return Ok(());
+    }
+    if struct_def
+        .generics
+        .lifetimes()
+        .any(|param| param.lifetime.ident == lifetime.ident)
+    {
+        return Err(Error::new_spanned(
+            lifetime,
+            format!(
+                "`{}` is already a lifetime parameter of this struct, choose a different name.",
+                lifetime
+            ),
+        ));
+    }
+    let this = format_ident!("this");
+    for field in struct_def.fields.iter_mut() {
+        if type_mentions_lifetime(&field.ty, &this) {
+            return Err(Error::new_spanned(
+                &field.ty,
+                format!(
+                    "Use `{}` instead of `'this`, since it was chosen as the internal lifetime.",
+                    lifetime
+                ),
+            ));
+        }
+        RenameLifetime {
+            from: &lifetime.ident,
+            to: &this,
+        }
+        .visit_type_mut(&mut field.ty);
+    }
+    Ok(())
+}
+
+fn replace_this_with_lifetime(typ: &Type, lifetime: &Ident) -> Type {
+    let mut typ = typ.clone();
+    RenameLifetime {
+        from: &format_ident!("this"),
+        to: lifetime,
+    }
+    .visit_type_mut(&mut typ);
+    typ
+}
+
+/// Finds out whether a lifetime is used somewhere.
+struct FindLifetime<'a> {
+    ident: &'a Ident,
+    found: bool,
+}
+
+impl<'ast> Visit<'ast> for FindLifetime<'_> {
+    fn visit_lifetime(&mut self, lifetime: &'ast Lifetime) {
+        self.found |= lifetime.ident == *self.ident;
+    }
+}
+
+fn type_mentions_lifetime(typ: &Type, ident: &Ident) -> bool {
+    let mut finder = FindLifetime {
+        ident,
+        found: false,
+    };
+    finder.visit_type(typ);
+    finder.found
+}
+
+/// Collects errors so that every problem with the input can be reported at once instead of only
+/// the first one.
+#[derive(Default)]
+struct Errors {
+    combined: Option<Error>,
+}
+
+impl Errors {
+    fn push(&mut self, error: Error) {
+        if let Some(combined) = &mut self.combined {
+            combined.combine(error);
+        } else {
+            self.combined = Some(error);
+        }
+    }
+
+    /// Records the error in `result` if there is one, otherwise returns its value.
+    fn record<T>(&mut self, result: Result<T, Error>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(error) => {
+                self.push(error);
+                None
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.combined.is_none()
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        match self.combined {
+            Some(combined) => Err(combined),
+            None => Ok(()),
+        }
+    }
+}
+
 fn handle_borrows_attr(
     field_info: &mut [StructFieldInfo],
-    attr: &Attribute,
+    items: Vec<BorrowItem>,
     borrows: &mut Vec<BorrowRequest>,
 ) -> Result<(), Error> {
-    let mut borrow_mut = false;
-    let mut waiting_for_comma = false;
-    let tokens = attr.tokens.clone();
-    let possible_error = Error::new_spanned(&tokens, "Invalid syntax for borrows() macro.");
-    let tokens = if let Some(TokenTree::Group(group)) = tokens.into_iter().next() {
-        group.stream()
-    } else {
-        return Err(possible_error);
-    };
-    for token in tokens {
-        if let TokenTree::Ident(ident) = token {
-            if waiting_for_comma {
-                return Err(Error::new_spanned(&ident, "Expected comma."));
-            }
-            let istr = ident.to_string();
-            if istr == "mut" {
-                if borrow_mut {
-                    return Err(Error::new_spanned(&ident, "Unexpected double 'mut'"));
-                }
-                borrow_mut = true;
-            } else {
-                let index = field_info.iter().position(|item| item.name == istr);
-                let index = if let Some(v) = index {
-                    v
-                } else {
-                    return Err(Error::new_spanned(
-                        &ident,
-                        concat!(
-                            "Unknown identifier, make sure that it is spelled ",
-                            "correctly and defined above the location it is borrowed."
-                        ),
-                    ));
-                };
-                if borrow_mut {
-                    if field_info[index].field_type == FieldType::Borrowed {
-                        return Err(Error::new_spanned(
-                            &ident,
-                            "Cannot borrow mutably, this field was previously borrowed immutably.",
-                        ));
-                    }
-                    if field_info[index].field_type == FieldType::BorrowedMut {
-                        return Err(Error::new_spanned(&ident, "Cannot borrow mutably twice."));
-                    }
-                    field_info[index].field_type = FieldType::BorrowedMut;
-                } else {
-                    if field_info[index].field_type == FieldType::BorrowedMut {
-                        return Err(Error::new_spanned(
-                            &ident,
-                            "Cannot borrow as immutable as it was previously borrowed mutably.",
-                        ));
-                    }
-                    field_info[index].field_type = FieldType::Borrowed;
-                }
-                borrows.push(BorrowRequest {
-                    index,
-                    mutable: borrow_mut,
-                });
-                waiting_for_comma = true;
-                borrow_mut = false;
+    let mut errors = Errors::default();
+    for BorrowItem {
+        container,
+        mutability,
+        name,
+    } in items
+    {
+        let borrow_mut = mutability.is_some();
+        let index = if let Some(v) = field_info.iter().position(|item| item.name == name) {
+            v
+        } else {
+            errors.push(Error::new_spanned(
+                &name,
+                "Unknown identifier, make sure that it is spelled correctly.",
+            ));
+            continue;
+        };
+        if borrow_mut {
+            if field_info[index].field_type == FieldType::Borrowed {
+                errors.push(Error::new_spanned(
+                    &name,
+                    "Cannot borrow mutably, this field was previously borrowed immutably.",
+                ));
+                continue;
             }
-        } else if let TokenTree::Punct(punct) = token {
-            if punct.as_char() == ',' {
-                if waiting_for_comma {
-                    waiting_for_comma = false;
-                } else {
-                    return Err(Error::new_spanned(&punct, "Unexpected extra comma."));
-                }
-            } else {
-                return Err(Error::new_spanned(
-                    &punct,
-                    "Unexpected punctuation, expected comma or identifier.",
+            if field_info[index].field_type == FieldType::BorrowedMut {
+                errors.push(Error::new_spanned(&name, "Cannot borrow mutably twice."));
+                continue;
+            }
+            field_info[index].field_type = FieldType::BorrowedMut;
+        } else {
+            if field_info[index].field_type == FieldType::BorrowedMut {
+                errors.push(Error::new_spanned(
+                    &name,
+                    "Cannot borrow as immutable as it was previously borrowed mutably.",
                 ));
+                continue;
             }
+            field_info[index].field_type = FieldType::Borrowed;
+        }
+        if borrows
+            .iter()
+            .any(|borrow| borrow.index == index && borrow.container == container.is_some())
+        {
+            errors.push(Error::new_spanned(
+                &name,
+                format!("`{}` is already borrowed by this field.", name),
+            ));
+            continue;
+        }
+        if container.is_some() {
+            field_info[index].container_borrowed = true;
         } else {
+            field_info[index].contents_borrowed = true;
+        }
+        borrows.push(BorrowRequest {
+            index,
+            mutable: borrow_mut,
+            container: container.is_some(),
+        });
+    }
+    errors.finish()
+}
+
+/// Returns the indexes of the fields in the order they must be constructed in. This is the order
+/// they were declared in, except that fields are moved after all the fields they borrow.
+fn construction_order(field_info: &[StructFieldInfo]) -> Result<Vec<usize>, Error> {
+    let mut built = vec![false; field_info.len()];
+    let mut order = Vec::new();
+    while order.len() < field_info.len() {
+        let next = (0..field_info.len()).find(|&index| {
+            !built[index]
+                && field_info[index]
+                    .borrows
+                    .iter()
+                    .all(|borrow| built[borrow.index])
+        });
+        if let Some(next) = next {
+            built[next] = true;
+            order.push(next);
+            continue;
+        }
+        // Every remaining field borrows another remaining field, so following those borrows
+        // eventually leads back to a field which has already been visited.
+        let mut path = vec![(0..field_info.len()).find(|&index| !built[index]).unwrap()];
+        loop {
+            let current = &field_info[*path.last().unwrap()];
+            let borrowed = current
+                .borrows
+                .iter()
+                .map(|borrow| borrow.index)
+                .find(|&index| !built[index])
+                .unwrap();
+            if let Some(start) = path.iter().position(|&index| index == borrowed) {
+                path.drain(..start);
+                path.push(borrowed);
+                break;
+            }
+            path.push(borrowed);
+        }
+        let mut message = format!(
+            "Fields cannot borrow each other in a cycle, but `{}` borrows `{}`",
+            field_info[path[0]].name, field_info[path[1]].name
+        );
+        for &index in &path[2..] {
+            message += &format!(", which borrows `{}`", field_info[index].name);
+        }
+        message += ".";
+        return Err(Error::new_spanned(&field_info[path[0]].name, message));
+    }
+    Ok(order)
+}
+
+/// Puts the fields in the given order, updating the indexes used by their borrows to match.
+fn reorder_fields(field_info: Vec<StructFieldInfo>, order: &[usize]) -> Vec<StructFieldInfo> {
+    let mut new_indexes = vec![0; order.len()];
+    for (new_index, &old_index) in order.iter().enumerate() {
+        new_indexes[old_index] = new_index;
+    }
+    let mut fields: Vec<_> = field_info.into_iter().map(Some).collect();
+    order
+        .iter()
+        .map(|&index| {
+            let mut field = fields[index].take().unwrap();
+            for borrow in &mut field.borrows {
+                borrow.index = new_indexes[borrow.index];
+            }
+            field
+        })
+        .collect()
+}
+
+/// Returns the indexes of the fields in the order they were declared in.
+fn declaration_order(field_info: &[StructFieldInfo]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..field_info.len()).collect();
+    order.sort_by_key(|&index| field_info[index].declaration_index);
+    order
+}
+
+/// Finds out whether a type contains a `&'this mut` reference.
+struct FindMutThisReference {
+    found: bool,
+}
+
+impl<'ast> Visit<'ast> for FindMutThisReference {
+    fn visit_type_reference(&mut self, reference: &'ast syn::TypeReference) {
+        if let (Some(_), Some(lifetime)) = (&reference.mutability, &reference.lifetime) {
+            self.found |= lifetime.ident == "this";
+        }
+        syn::visit::visit_type_reference(self, reference);
+    }
+}
+
+/// Catches a field whose type does not match how it borrows other fields. Returns an error for
+/// mistakes that cannot work and adds warnings for ones which are most likely unintended.
+fn validate_field(field: &StructFieldInfo, warnings: &mut Vec<TokenStream2>) -> Result<(), Error> {
+    let this = format_ident!("this");
+    let mentions_this = type_mentions_lifetime(&field.typ, &this);
+    if field.borrows.is_empty() {
+        if mentions_this {
             return Err(Error::new_spanned(
-                &token,
-                "Unexpected token, expected comma or identifier.",
+                &field.typ,
+                format!(
+                    concat!(
+                        "`{0}` uses 'this but does not borrow any fields. Add ",
+                        "#[borrows(...)] to `{0}` listing the fields it refers to."
+                    ),
+                    field.name
+                ),
             ));
         }
+        return Ok(());
+    }
+    // Fields which are borrowed themselves can be derived from other fields without holding
+    // on to them, E.G. a `String` built from an earlier field and borrowed by a later one.
+    if !mentions_this && field.field_type.is_tail() {
+        warnings.push(make_warning(
+            field.name.span(),
+            "borrows_without_this",
+            &format!(
+                concat!(
+                    "`{}` borrows other fields but its type never mentions 'this, so it ",
+                    "cannot keep any references to them."
+                ),
+                field.name
+            ),
+        ));
+    }
+    let mut finder = FindMutThisReference { found: false };
+    finder.visit_type(&field.typ);
+    if finder.found && field.borrows.iter().all(|borrow| !borrow.mutable) {
+        warnings.push(make_warning(
+            field.name.span(),
+            "mutable_reference_from_immutable_borrow",
+            &format!(
+                concat!(
+                    "`{}` contains a `&'this mut` reference but only borrows fields ",
+                    "immutably, use #[borrows(mut ...)] to borrow a field mutably."
+                ),
+                field.name
+            ),
+        ));
     }
     Ok(())
 }
 
+/// Produces a warning pointing at `span`. Procedural macros cannot emit warnings directly, so this
+/// uses a deprecated item named `name` whose deprecation note is the message.
+fn make_warning(span: Span, name: &str, message: &str) -> TokenStream2 {
+    let name = Ident::new(name, span);
+    quote_spanned! { span =>
+        const _: () = {
+            #[deprecated(note = #message)]
+            #[allow(non_camel_case_types)]
+            struct #name;
+            let _ = #name;
+        };
+    }
+}
+
 /// Creates the struct that will actually store the data. This involves properly organizing the
-/// fields, collecting metadata about them, reversing the order everything is stored in, and
-/// converting any uses of 'this to 'static.
+/// fields, collecting metadata about them, sorting them into the order they must be constructed
+/// in, wrapping them in the types they are stored as, and converting any uses of 'this to the
+/// lifetime returned by erased_lifetime. Every problem found in the fields is reported, not just
+/// the first one.
 fn create_actual_struct(
     original_struct_def: &ItemStruct,
 ) -> Result<(TokenStream2, Vec<StructFieldInfo>), Error> {
     let mut actual_struct_def = original_struct_def.clone();
-    actual_struct_def.vis = syn::parse_quote! { pub };
     let mut field_info = Vec::new();
+    let mut errors = Errors::default();
+    // Fields whose borrows could not be understood are not validated further, since whatever is
+    // wrong with them has already been reported.
+    let mut invalid_borrows = Vec::new();
+    // The attributes of each field, which are handled once every field is known since fields can
+    // borrow fields declared after them.
+    let mut borrows_attrs = Vec::new();
     match &mut actual_struct_def.fields {
         Fields::Named(fields) => {
             for field in &mut fields.named {
-                let mut borrows = Vec::new();
-                for (index, attr) in field.attrs.iter().enumerate() {
-                    let path = &attr.path;
-                    if path.leading_colon.is_some() {
-                        continue;
-                    }
-                    if path.segments.len() != 1 {
-                        continue;
-                    }
-                    if path.segments.first().unwrap().ident == "borrows" {
-                        handle_borrows_attr(&mut field_info[..], attr, &mut borrows)?;
-                        field.attrs.remove(index);
-                        break;
+                let mut field_borrows_attrs = Vec::new();
+                let mut field_options = FieldOptions::default();
+                // Borrows and options for the field are consumed here, the attributes themselves
+                // are not valid in the final struct definition. A field may have any number of
+                // #[borrows] attributes, which are merged in order.
+                let mut remaining_attrs = Vec::new();
+                for attr in field.attrs.drain(..) {
+                    if parse::attr_is(&attr, "borrows") {
+                        field_borrows_attrs.push(attr);
+                    } else if parse::attr_is(&attr, "ouroboros") {
+                        field_options.add_attr(&attr, &mut errors);
+                    } else {
+                        remaining_attrs.push(attr);
                     }
                 }
+                field.attrs = remaining_attrs;
                 field.attrs.push(syn::parse_quote! { #[doc(hidden)] });
+                borrows_attrs.push(field_borrows_attrs);
                 field_info.push(StructFieldInfo {
                     name: field.ident.clone().expect("Named field has no name."),
                     typ: field.ty.clone(),
+                    declaration_index: field_info.len(),
                     field_type: FieldType::Tail,
-                    borrows,
+                    borrows: Vec::new(),
+                    contents_borrowed: false,
+                    container_borrowed: false,
+                    option_inner: optional_inner_type(&field.ty).cloned(),
+                    options: field_options,
                 });
             }
         }
@@ -343,6 +866,23 @@ fn create_actual_struct(
             "Self-referencing structs must have at least 2 fields.",
         ));
     }
+    for (index, attrs) in borrows_attrs.into_iter().enumerate() {
+        let mut borrows = Vec::new();
+        for attr in attrs {
+            let result = parse::parse_borrows_attr(&attr)
+                .and_then(|items| handle_borrows_attr(&mut field_info[..], items, &mut borrows));
+            if errors.record(result).is_none() && !invalid_borrows.contains(&index) {
+                invalid_borrows.push(index);
+            }
+        }
+        field_info[index].borrows = borrows;
+    }
+    let mut warnings = Vec::new();
+    for (index, field) in field_info.iter().enumerate() {
+        if !invalid_borrows.contains(&index) {
+            errors.record(validate_field(field, &mut warnings));
+        }
+    }
     let mut has_non_tail = false;
     for field in &field_info {
         if !field.field_type.is_tail() {
@@ -350,63 +890,329 @@ fn create_actual_struct(
             break;
         }
     }
-    if !has_non_tail {
-        return Err(Error::new(
+    // Mistakes in individual fields usually explain why nothing is borrowed.
+    if !has_non_tail && errors.is_empty() {
+        errors.push(Error::new(
             Span::call_site(),
-            &format!(
+            format!(
                 concat!(
                     "Self-referencing struct cannot be made entirely of tail fields, try adding ",
-                    "#[borrows({0})] to a field defined after {0}."
+                    "#[borrows({0})] to another field."
                 ),
                 field_info[0].name
             ),
         ));
     }
-    // Reverse the order of all fields. We ensure that items in the struct are only dependent
-    // on references to items above them. Rust drops items in a struct in forward declaration order.
-    // This would cause parents being dropped before children, necessitating the reversal.
-    match &mut actual_struct_def.fields {
+    let order = errors.record(construction_order(&field_info));
+    errors.finish()?;
+    let order = order.expect("Errors are reported above.");
+    // Fields are stored in the order they were declared in, so that layouts such as
+    // `#[repr(C)]` work as expected. Since Rust would drop them in that order as well, they are
+    // wrapped in `ManuallyDrop` and the Drop implementation from make_drop_impl drops them in
+    // the reverse of the order they are constructed in instead. Fields which are borrowed as a
+    // whole are also boxed so that references to them remain valid when the struct is moved.
+    // The fields live in a separate storage struct which the actual struct only holds through a
+    // `Hidden`, since only the generated methods may access them.
+    let mut storage_def = original_struct_def.clone();
+    storage_def.ident = storage_name(&original_struct_def.ident);
+    storage_def.vis = syn::Visibility::Inherited;
+    storage_def.attrs = original_struct_def
+        .attrs
+        .iter()
+        .filter(|attr| parse::attr_is(attr, "repr"))
+        .cloned()
+        .collect();
+    storage_def.attrs.push(syn::parse_quote! { #[doc(hidden)] });
+    match &mut storage_def.fields {
         Fields::Named(fields) => {
-            let reversed = fields.named.iter().rev().cloned().collect();
-            fields.named = reversed;
+            for (field, (actual_field, info)) in fields
+                .named
+                .iter_mut()
+                .zip(actual_struct_def.fields.iter().zip(field_info.iter()))
+            {
+                field.attrs = actual_field.attrs.clone();
+                field.vis = syn::Visibility::Inherited;
+                field.ty = info.stored_type();
+            }
         }
         Fields::Unnamed(_fields) => unreachable!("Error handled earlier."),
         Fields::Unit => unreachable!("Error handled earlier."),
     }
-    // Finally, replace the fake 'this lifetime with 'static.
-    let actual_struct_def = replace_this_with_static(quote! { #actual_struct_def });
+    let storage_name = &storage_def.ident;
+    let storage_args = make_generic_arguments(&original_struct_def.generics);
+    // The braces keep their span so that errors pointing at the struct still point at it.
+    let hidden_fields: syn::FieldsNamed = syn::parse_quote! {{
+        #[doc(hidden)]
+        __ouroboros_fields: ::ouroboros::macro_help::Hidden<#storage_name <#(#storage_args),*>>
+    }};
+    if let Fields::Named(fields) = &mut actual_struct_def.fields {
+        fields.named = hidden_fields.named;
+    }
+    let field_info = reorder_fields(field_info, &order);
+    // Finally, replace the fake 'this lifetime with a real one.
+    RenameLifetime {
+        from: &format_ident!("this"),
+        to: &erased_lifetime(&original_struct_def.generics),
+    }
+    .visit_item_struct_mut(&mut storage_def);
+    let actual_struct_def = quote! {
+        #actual_struct_def
+        #storage_def
+        #(#warnings)*
+    };
 
     Ok((actual_struct_def, field_info))
 }
 
-// Takes the generics parameters from the original struct and turns them into arguments.
-fn make_generic_arguments(generic_params: &Generics) -> Vec<TokenStream2> {
-    let mut arguments = Vec::new();
-    for generic in generic_params.params.clone() {
-        match generic {
-            GenericParam::Type(typ) => {
-                let ident = &typ.ident;
-                arguments.push(quote! { #ident });
-            }
-            GenericParam::Lifetime(lt) => {
-                let lifetime = &lt.lifetime;
-                arguments.push(quote! { #lifetime });
-            }
-            GenericParam::Const(_) => unimplemented!("Const generics are not supported yet."),
-        }
-    }
-    arguments
+// The helper types are emitted next to the struct they belong to, so their names start with the
+// name of the struct to keep them apart from the helpers of other structs in the same scope.
+fn storage_name(struct_name: &Ident) -> Ident {
+    format_ident!("{}Storage", struct_name)
+}
+
+/// Returns a statement binding `storage` to a reference to the fields of `instance`, which is
+/// mutable if `mutable` is true. Places from make_field_place are then made with `storage` as
+/// their base.
+fn bind_storage(instance: TokenStream2, mutable: bool) -> TokenStream2 {
+    if mutable {
+        quote! {
+            let storage = unsafe {
+                ::ouroboros::macro_help::Hidden::get_mut(&mut #instance.__ouroboros_fields)
+            };
+        }
+    } else {
+        quote! {
+            let storage = unsafe {
+                ::ouroboros::macro_help::Hidden::get(&#instance.__ouroboros_fields)
+            };
+        }
+    }
+}
+
+/// Like bind_storage, but binds a raw pointer to the fields of the struct `instance` points to,
+/// for code which works with a struct that is not fully initialized. Places are then made with
+/// `(*storage)` as their base.
+fn bind_storage_ptr(instance: TokenStream2) -> TokenStream2 {
+    quote! {
+        let storage = ::ouroboros::macro_help::Hidden::as_mut_ptr(unsafe {
+            ::core::ptr::addr_of_mut!((*#instance).__ouroboros_fields)
+        });
+    }
+}
+
+fn heads_name(struct_name: &Ident) -> Ident {
+    format_ident!("{}Heads", struct_name)
+}
+
+fn borrowed_fields_name(struct_name: &Ident) -> Ident {
+    format_ident!("{}BorrowedFields", struct_name)
+}
+
+fn borrowed_mut_fields_name(struct_name: &Ident) -> Ident {
+    format_ident!("{}BorrowedMutFields", struct_name)
+}
+
+/// Returns a copy of `generics` with only the parameters for which `is_used` returns true. Bounds
+/// and `where` clauses which mention a parameter that is left out are left out as well.
+fn retain_generics(generics: &Generics, is_used: impl Fn(&Ident) -> bool) -> Generics {
+    let unused: Vec<Ident> = generics
+        .params
+        .iter()
+        .map(|param| generic_param_ident(param).clone())
+        .filter(|ident| !is_used(ident))
+        .collect();
+    let mentions_unused = |tokens: TokenStream2| {
+        unused
+            .iter()
+            .any(|ident| tokens_mention(tokens.clone(), ident))
+    };
+
+    let mut result = generics.clone();
+    result.params = generics
+        .params
+        .iter()
+        .filter_map(|param| {
+            let mut param = param.clone();
+            match &mut param {
+                GenericParam::Type(typ) => {
+                    if unused.contains(&typ.ident) {
+                        return None;
+                    }
+                    typ.bounds = typ
+                        .bounds
+                        .iter()
+                        .filter(|bound| !mentions_unused(quote! { #bound }))
+                        .cloned()
+                        .collect();
+                    if typ.bounds.is_empty() {
+                        typ.colon_token = None;
+                    }
+                    if let Some(default) = &typ.default {
+                        if mentions_unused(quote! { #default }) {
+                            typ.eq_token = None;
+                            typ.default = None;
+                        }
+                    }
+                }
+                GenericParam::Lifetime(lt) => {
+                    if unused.contains(&lt.lifetime.ident) {
+                        return None;
+                    }
+                    lt.bounds = lt
+                        .bounds
+                        .iter()
+                        .filter(|bound| !mentions_unused(quote! { #bound }))
+                        .cloned()
+                        .collect();
+                    if lt.bounds.is_empty() {
+                        lt.colon_token = None;
+                    }
+                }
+                GenericParam::Const(constant) => {
+                    if unused.contains(&constant.ident) {
+                        return None;
+                    }
+                }
+            }
+            Some(param)
+        })
+        .collect();
+    if let Some(where_clause) = &mut result.where_clause {
+        where_clause.predicates = where_clause
+            .predicates
+            .iter()
+            .filter(|predicate| !mentions_unused(quote! { #predicate }))
+            .cloned()
+            .collect();
+    }
+    result
+}
+
+fn generic_param_ident(param: &GenericParam) -> &Ident {
+    match param {
+        GenericParam::Type(typ) => &typ.ident,
+        GenericParam::Lifetime(lt) => &lt.lifetime.ident,
+        GenericParam::Const(constant) => &constant.ident,
+    }
+}
+
+/// Builders only take the generic parameters of the struct which are mentioned in
+/// `builder_tokens`, since any other parameter would need a marker field which callers would have
+/// to fill in. The rest are declared by the build functions instead. Returns the parameters of the
+/// builder along with the parameters and `where` clause to add to the build functions, which
+/// contains the bounds the builder left out.
+fn split_builder_generics(
+    generic_params: &Generics,
+    builder_tokens: TokenStream2,
+) -> (Generics, TokenStream2, TokenStream2) {
+    let builder_generics = retain_generics(generic_params, |ident| {
+        tokens_mention(builder_tokens.clone(), ident)
+    });
+    let is_fn_param = |ident: &Ident| {
+        !builder_generics
+            .params
+            .iter()
+            .any(|param| generic_param_ident(param) == ident)
+    };
+    let fn_idents: Vec<&Ident> = generic_params
+        .params
+        .iter()
+        .map(generic_param_ident)
+        .filter(|ident| is_fn_param(ident))
+        .collect();
+    if fn_idents.is_empty() {
+        return (builder_generics, quote! {}, quote! {});
+    }
+    let mentions_fn_param = |tokens: TokenStream2| {
+        fn_idents
+            .iter()
+            .any(|ident| tokens_mention(tokens.clone(), ident))
+    };
+
+    let mut fn_params = Vec::new();
+    let mut predicates = Vec::new();
+    for param in &generic_params.params {
+        match param {
+            GenericParam::Type(typ) if is_fn_param(&typ.ident) => {
+                let (ident, bounds) = (&typ.ident, &typ.bounds);
+                fn_params.push(quote! { #ident: #bounds });
+            }
+            GenericParam::Type(typ) => {
+                let ident = &typ.ident;
+                let bounds: Vec<_> = typ
+                    .bounds
+                    .iter()
+                    .filter(|bound| mentions_fn_param(quote! { #bound }))
+                    .collect();
+                if !bounds.is_empty() {
+                    predicates.push(quote! { #ident: #(#bounds)+* });
+                }
+            }
+            GenericParam::Lifetime(lt) if is_fn_param(&lt.lifetime.ident) => {
+                let (lifetime, bounds) = (&lt.lifetime, &lt.bounds);
+                fn_params.push(quote! { #lifetime: #bounds });
+            }
+            GenericParam::Lifetime(lt) => {
+                let lifetime = &lt.lifetime;
+                let bounds: Vec<_> = lt
+                    .bounds
+                    .iter()
+                    .filter(|bound| mentions_fn_param(quote! { #bound }))
+                    .collect();
+                if !bounds.is_empty() {
+                    predicates.push(quote! { #lifetime: #(#bounds)+* });
+                }
+            }
+            GenericParam::Const(constant) if is_fn_param(&constant.ident) => {
+                let (ident, typ) = (&constant.ident, &constant.ty);
+                fn_params.push(quote! { const #ident: #typ });
+            }
+            GenericParam::Const(_) => (),
+        }
+    }
+    if let Some(where_clause) = &generic_params.where_clause {
+        for predicate in &where_clause.predicates {
+            if mentions_fn_param(quote! { #predicate }) {
+                predicates.push(quote! { #predicate });
+            }
+        }
+    }
+    (
+        builder_generics,
+        quote! { <#(#fn_params),*> },
+        quote! { where #(#predicates),* },
+    )
+}
+
+// Takes the generics parameters from the original struct and turns them into arguments.
+fn make_generic_arguments(generic_params: &Generics) -> Vec<TokenStream2> {
+    let mut arguments = Vec::new();
+    for generic in generic_params.params.clone() {
+        match generic {
+            GenericParam::Type(typ) => {
+                let ident = &typ.ident;
+                arguments.push(quote! { #ident });
+            }
+            GenericParam::Lifetime(lt) => {
+                let lifetime = &lt.lifetime;
+                arguments.push(quote! { #lifetime });
+            }
+            GenericParam::Const(_) => unimplemented!("Const generics are not supported yet."),
+        }
+    }
+    arguments
 }
 
 fn create_builder_and_constructor(
     struct_name: &Ident,
+    visibility: &syn::Visibility,
     builder_struct_name: &Ident,
     generic_params: &Generics,
     generic_args: &[TokenStream2],
     field_info: &[StructFieldInfo],
-    do_chain_hack: bool,
-    do_no_doc: bool,
+    options: &Options,
 ) -> Result<(TokenStream2, TokenStream2), Error> {
+    let erased = erased_lifetime(generic_params);
     let documentation = format!(
         concat!(
             "Constructs a new instance of this self-referential struct. (See also ",
@@ -415,7 +1221,7 @@ fn create_builder_and_constructor(
             "using functions instead of directly passing their value. The arguments are as ",
             "follows:\n\n| Argument | Suggested Use |\n| --- | --- |\n",
         ),
-        builder_struct_name.to_string()
+        builder_struct_name
     );
     let builder_documentation = concat!(
         "A more verbose but stable way to construct self-referencing structs. It is ",
@@ -431,34 +1237,35 @@ fn create_builder_and_constructor(
             "Calls [`{0}::new()`]({0}::new) using the provided values. This is preferrable over ",
             "calling `new()` directly for the reasons listed above. "
         ),
-        struct_name.to_string()
+        struct_name
     );
     let mut doc_table = "".to_owned();
     let mut code: Vec<TokenStream2> = Vec::new();
     let mut params: Vec<TokenStream2> = Vec::new();
-    let mut builder_struct_generic_producers: Vec<_> = generic_params
-        .params
-        .iter()
-        .map(|param| quote! { #param })
-        .collect();
-    let mut builder_struct_generic_consumers = Vec::from(generic_args);
+    // The generic parameters of the struct are added to these once it is known which of them the
+    // builder uses.
+    let mut builder_struct_generic_producers = Vec::new();
+    let mut builder_struct_generic_consumers = Vec::new();
     let mut builder_struct_fields = Vec::new();
     let mut builder_struct_field_names = Vec::new();
 
     code.push(quote! { let mut result = ::core::mem::MaybeUninit::<Self>::uninit(); });
+    code.push(bind_storage_ptr(quote! { result.as_mut_ptr() }));
 
-    for field in field_info {
+    // Arguments are taken in the order the fields were declared in.
+    for index in declaration_order(field_info) {
+        let field = &field_info[index];
         let field_name = &field.name;
 
-        let arg_type = make_constructor_arg_type(&field, &field_info[..], do_chain_hack)?;
+        let arg_type = make_constructor_arg_type(field, field_info);
         if let ArgType::Plain(plain_type) = arg_type {
             // No fancy builder function, we can just move the value directly into the struct.
             params.push(quote! { #field_name: #plain_type });
-            builder_struct_fields.push(quote! { #field_name: #plain_type });
+            builder_struct_fields.push(quote! { pub #field_name: #plain_type });
             builder_struct_field_names.push(quote! { #field_name });
             doc_table += &format!(
                 "| `{}` | Directly pass in the value this field should contain |\n",
-                field_name.to_string()
+                field_name
             );
         } else if let ArgType::TraitBound(bound_type) = arg_type {
             // Trait bounds are much trickier. We need a special syntax to accept them in the
@@ -469,37 +1276,32 @@ fn create_builder_and_constructor(
             // Ok so hear me out basically without this thing here my IDE thinks the rest of the
             // code is a string and it all turns green.
             {}
-            doc_table += &format!(
-                "| `{}` | Use a function or closure: `(",
-                builder_name.to_string()
-            );
-            let mut builder_args = Vec::new();
-            for (index, borrow) in field.borrows.iter().enumerate() {
-                let borrowed_name = &field_info[borrow.index].name;
-                builder_args.push(format_ident!("{}_illegal_static_reference", borrowed_name));
-                doc_table += &format!(
-                    "{}: &{}_",
-                    borrowed_name.to_string(),
-                    if borrow.mutable { "mut " } else { "" },
-                );
-                if index < field.borrows.len() - 1 {
-                    doc_table += ", ";
-                }
-            }
-            doc_table += &format!(") -> {}: _` | \n", field_name.to_string());
-            code.push(quote! { let #field_name = #builder_name (#(#builder_args),*); });
+            doc_table += &format!("| `{}` | Use a function or closure: `(", builder_name);
+            doc_table += &make_builder_doc_args(field, field_info);
+            doc_table += &format!(") -> {}: _` | \n", field_name);
             let generic_type_name =
                 format_ident!("{}Builder_", field_name.to_string().to_class_case());
 
             builder_struct_generic_producers.push(quote! { #generic_type_name: #bound_type });
             builder_struct_generic_consumers.push(quote! { #generic_type_name });
-            builder_struct_fields.push(quote! { #builder_name: #generic_type_name });
+            builder_struct_fields.push(quote! { pub #builder_name: #generic_type_name });
             builder_struct_field_names.push(quote! { #builder_name });
         }
-        let field_type = &field.typ;
-        let field_type = replace_this_with_static(quote! { #field_type });
+    }
+
+    // Fields are built in the order they are stored in field_info, which makes sure everything a
+    // field borrows already exists.
+    for field in field_info {
+        let field_name = &field.name;
+        if !field.borrows.is_empty() {
+            let builder_name = field.builder_name();
+            let builder_args = field.builder_args(field_info);
+            code.push(quote! { let #field_name = #builder_name (#(#builder_args),*); });
+        }
+        let stored_type = replace_this_with_lifetime(&field.stored_type(), &erased);
+        let stored_value = field.make_stored_value(quote! { #field_name });
         code.push(quote! { unsafe {
-            ((&mut (*result.as_mut_ptr()).#field_name) as *mut #field_type).write(#field_name);
+            ((&mut (*storage).#field_name) as *mut #stored_type).write(#stored_value);
         }});
 
         if field.field_type == FieldType::Borrowed {
@@ -509,7 +1311,22 @@ fn create_builder_and_constructor(
         }
     }
 
-    let documentation = if !do_no_doc {
+    let (builder_generics, build_fn_generics, build_fn_where_clause) = split_builder_generics(
+        generic_params,
+        quote! { #(#builder_struct_fields)* #(#builder_struct_generic_producers)* },
+    );
+    let mut producers: Vec<_> = builder_generics
+        .params
+        .iter()
+        .map(|param| quote! { #param })
+        .collect();
+    producers.append(&mut builder_struct_generic_producers);
+    let builder_struct_generic_producers = producers;
+    let mut consumers = make_generic_arguments(&builder_generics);
+    consumers.append(&mut builder_struct_generic_consumers);
+    let builder_struct_generic_consumers = consumers;
+
+    let documentation = if !options.do_no_doc {
         let documentation = documentation + &doc_table;
         quote! {
             #[doc=#documentation]
@@ -518,7 +1335,7 @@ fn create_builder_and_constructor(
         quote! { #[doc(hidden)] }
     };
 
-    let builder_documentation = if !do_no_doc {
+    let builder_documentation = if !options.do_no_doc {
         let builder_documentation = builder_documentation + &doc_table;
         quote! {
             #[doc=#builder_documentation]
@@ -536,12 +1353,14 @@ fn create_builder_and_constructor(
     };
     let builder_def = quote! {
         #builder_documentation
-        pub struct #builder_struct_name <#(#builder_struct_generic_producers),*> {
-            #(pub #builder_struct_fields),*
+        #visibility struct #builder_struct_name <#(#builder_struct_generic_producers),*> {
+            #(#builder_struct_fields,)*
         }
         impl<#(#builder_struct_generic_producers),*> #builder_struct_name <#(#builder_struct_generic_consumers),*> {
             #[doc=#build_fn_documentation]
-            pub fn build(self) -> #struct_name <#(#generic_args),*> {
+            pub fn build #build_fn_generics (self) -> #struct_name <#(#generic_args),*>
+            #build_fn_where_clause
+            {
                 #struct_name::new(
                     #(self.#builder_struct_field_names),*
                 )
@@ -553,13 +1372,15 @@ fn create_builder_and_constructor(
 
 fn create_try_builder_and_constructor(
     struct_name: &Ident,
+    visibility: &syn::Visibility,
     builder_struct_name: &Ident,
     generic_params: &Generics,
     generic_args: &[TokenStream2],
     field_info: &[StructFieldInfo],
-    do_chain_hack: bool,
-    do_no_doc: bool,
+    options: &Options,
 ) -> Result<(TokenStream2, TokenStream2), Error> {
+    let erased = erased_lifetime(generic_params);
+    let heads_name = heads_name(struct_name);
     let mut head_recover_code = Vec::new();
     for field in field_info {
         if field.borrows.is_empty() {
@@ -567,6 +1388,9 @@ fn create_try_builder_and_constructor(
             head_recover_code.push(quote! { #field_name });
         }
     }
+    if heads_phantom_type(field_info, generic_params).is_some() {
+        head_recover_code.push(quote! { _phantom: ::core::marker::PhantomData });
+    }
     let mut current_head_index = 0;
 
     let documentation = format!(
@@ -577,7 +1401,7 @@ fn create_try_builder_and_constructor(
             "succeed, `Ok` is returned. The arguments are as follows:\n\n",
             "| Argument | Suggested Use |\n| --- | --- |\n",
         ),
-        builder_struct_name.to_string()
+        builder_struct_name
     );
     let or_recover_documentation = format!(
         concat!(
@@ -587,7 +1411,7 @@ fn create_try_builder_and_constructor(
             "are returned in the case of an error. The arguments are as follows:\n\n",
             "| Argument | Suggested Use |\n| --- | --- |\n",
         ),
-        builder_struct_name.to_string()
+        builder_struct_name
     );
     let builder_documentation = concat!(
         "A more verbose but stable way to construct self-referencing structs. It is ",
@@ -604,7 +1428,7 @@ fn create_try_builder_and_constructor(
             "Calls [`{0}::try_new()`]({0}::try_new) using the provided values. This is ",
             "preferrable over calling `try_new()` directly for the reasons listed above. "
         ),
-        struct_name.to_string()
+        struct_name
     );
     let build_or_recover_fn_documentation = format!(
         concat!(
@@ -612,39 +1436,36 @@ fn create_try_builder_and_constructor(
             "values. This is preferrable over calling `try_new_or_recover()` directly for the ",
             "reasons listed above. "
         ),
-        struct_name.to_string()
+        struct_name
     );
     let mut doc_table = "".to_owned();
     let mut or_recover_code: Vec<TokenStream2> = Vec::new();
     let mut params: Vec<TokenStream2> = Vec::new();
-    let mut builder_struct_generic_producers: Vec<_> = generic_params
-        .params
-        .iter()
-        .map(|param| quote! { #param })
-        .collect();
-    let mut builder_struct_generic_consumers = Vec::from(generic_args);
+    // The generic parameters of the struct are added to these once it is known which of them the
+    // builder uses.
+    let mut builder_struct_generic_producers = Vec::new();
+    let mut builder_struct_generic_consumers = Vec::new();
     let mut builder_struct_fields = Vec::new();
     let mut builder_struct_field_names = Vec::new();
 
     or_recover_code.push(quote! { let mut result = ::core::mem::MaybeUninit::<Self>::uninit(); });
+    or_recover_code.push(bind_storage_ptr(quote! { result.as_mut_ptr() }));
 
-    for field in field_info {
+    // Arguments are taken in the order the fields were declared in.
+    for index in declaration_order(field_info) {
+        let field = &field_info[index];
         let field_name = &field.name;
 
-        let arg_type = make_try_constructor_arg_type(&field, &field_info[..], do_chain_hack)?;
+        let arg_type = make_try_constructor_arg_type(field, field_info);
         if let ArgType::Plain(plain_type) = arg_type {
             // No fancy builder function, we can just move the value directly into the struct.
             params.push(quote! { #field_name: #plain_type });
-            builder_struct_fields.push(quote! { #field_name: #plain_type });
+            builder_struct_fields.push(quote! { pub #field_name: #plain_type });
             builder_struct_field_names.push(quote! { #field_name });
             doc_table += &format!(
                 "| `{}` | Directly pass in the value this field should contain |\n",
-                field_name.to_string()
+                field_name
             );
-            head_recover_code[current_head_index] = quote! {
-                #field_name: unsafe { ::core::ptr::read(&(*result.as_ptr()).#field_name as *const _) }
-            };
-            current_head_index += 1;
         } else if let ArgType::TraitBound(bound_type) = arg_type {
             // Trait bounds are much trickier. We need a special syntax to accept them in the
             // contructor, and generic parameters need to be added to the builder struct to make
@@ -654,43 +1475,44 @@ fn create_try_builder_and_constructor(
             // Ok so hear me out basically without this thing here my IDE thinks the rest of the
             // code is a string and it all turns green.
             {}
-            doc_table += &format!(
-                "| `{}` | Use a function or closure: `(",
-                builder_name.to_string()
-            );
-            let mut builder_args = Vec::new();
-            for (index, borrow) in field.borrows.iter().enumerate() {
-                let borrowed_name = &field_info[borrow.index].name;
-                builder_args.push(format_ident!("{}_illegal_static_reference", borrowed_name));
-                doc_table += &format!(
-                    "{}: &{}_",
-                    borrowed_name.to_string(),
-                    if borrow.mutable { "mut " } else { "" },
-                );
-                if index < field.borrows.len() - 1 {
-                    doc_table += ", ";
-                }
-            }
-            doc_table += &format!(") -> Result<{}: _, Error_>` | \n", field_name.to_string());
-            or_recover_code.push(quote! {
-                let #field_name = match #builder_name (#(#builder_args),*) {
-                    ::core::result::Result::Ok(value) => value,
-                    ::core::result::Result::Err(err)
-                        => return ::core::result::Result::Err((err, Heads { #(#head_recover_code),* })),
-                };
-            });
+            doc_table += &format!("| `{}` | Use a function or closure: `(", builder_name);
+            doc_table += &make_builder_doc_args(field, field_info);
+            doc_table += &format!(") -> Result<{}: _, Error_>` | \n", field_name);
             let generic_type_name =
                 format_ident!("{}Builder_", field_name.to_string().to_class_case());
 
             builder_struct_generic_producers.push(quote! { #generic_type_name: #bound_type });
             builder_struct_generic_consumers.push(quote! { #generic_type_name });
-            builder_struct_fields.push(quote! { #builder_name: #generic_type_name });
+            builder_struct_fields.push(quote! { pub #builder_name: #generic_type_name });
             builder_struct_field_names.push(quote! { #builder_name });
         }
-        let field_type = &field.typ;
-        let field_type = replace_this_with_static(quote! { #field_type });
+    }
+
+    // Fields are built in the order they are stored in field_info, which makes sure everything a
+    // field borrows already exists.
+    for field in field_info {
+        let field_name = &field.name;
+        if field.borrows.is_empty() {
+            let recovered_value = field.make_unstored_value(quote! {
+                unsafe { ::core::ptr::read(&(*storage).#field_name as *const _) }
+            });
+            head_recover_code[current_head_index] = quote! { #field_name: #recovered_value };
+            current_head_index += 1;
+        } else {
+            let builder_name = field.builder_name();
+            let builder_args = field.builder_args(field_info);
+            or_recover_code.push(quote! {
+                let #field_name = match #builder_name (#(#builder_args),*) {
+                    ::core::result::Result::Ok(value) => value,
+                    ::core::result::Result::Err(err)
+                        => return ::core::result::Result::Err((err, #heads_name { #(#head_recover_code),* })),
+                };
+            });
+        }
+        let stored_type = replace_this_with_lifetime(&field.stored_type(), &erased);
+        let stored_value = field.make_stored_value(quote! { #field_name });
         let line = quote! { unsafe {
-            ((&mut (*result.as_mut_ptr()).#field_name) as *mut #field_type).write(#field_name);
+            ((&mut (*storage).#field_name) as *mut #stored_type).write(#stored_value);
         }};
         or_recover_code.push(line);
 
@@ -700,7 +1522,22 @@ fn create_try_builder_and_constructor(
             or_recover_code.push(field.make_illegal_static_mut_reference());
         }
     }
-    let documentation = if !do_no_doc {
+    let (builder_generics, build_fn_generics, build_fn_where_clause) = split_builder_generics(
+        generic_params,
+        quote! { #(#builder_struct_fields)* #(#builder_struct_generic_producers)* },
+    );
+    let mut producers: Vec<_> = builder_generics
+        .params
+        .iter()
+        .map(|param| quote! { #param })
+        .collect();
+    producers.append(&mut builder_struct_generic_producers);
+    let mut builder_struct_generic_producers = producers;
+    let mut consumers = make_generic_arguments(&builder_generics);
+    consumers.append(&mut builder_struct_generic_consumers);
+    let mut builder_struct_generic_consumers = consumers;
+
+    let documentation = if !options.do_no_doc {
         let documentation = documentation + &doc_table;
         quote! {
             #[doc=#documentation]
@@ -708,7 +1545,7 @@ fn create_try_builder_and_constructor(
     } else {
         quote! { #[doc(hidden)] }
     };
-    let or_recover_documentation = if !do_no_doc {
+    let or_recover_documentation = if !options.do_no_doc {
         let or_recover_documentation = or_recover_documentation + &doc_table;
         quote! {
             #[doc=#or_recover_documentation]
@@ -716,7 +1553,7 @@ fn create_try_builder_and_constructor(
     } else {
         quote! { #[doc(hidden)] }
     };
-    let builder_documentation = if !do_no_doc {
+    let builder_documentation = if !options.do_no_doc {
         let builder_documentation = builder_documentation + &doc_table;
         quote! {
             #[doc=#builder_documentation]
@@ -730,7 +1567,7 @@ fn create_try_builder_and_constructor(
             Self::try_new_or_recover(#(#builder_struct_field_names),*).map_err(|(error, _heads)| error)
         }
         #or_recover_documentation
-        pub fn try_new_or_recover<Error_>(#(#params),*) -> ::core::result::Result<Self, (Error_, Heads<#(#generic_args),*>)> {
+        pub fn try_new_or_recover<Error_>(#(#params),*) -> ::core::result::Result<Self, (Error_, #heads_name<#(#generic_args),*>)> {
             #(#or_recover_code)*
             ::core::result::Result::Ok(unsafe { result.assume_init() })
         }
@@ -739,18 +1576,22 @@ fn create_try_builder_and_constructor(
     builder_struct_generic_consumers.push(quote! { Error_ });
     let builder_def = quote! {
         #builder_documentation
-        pub struct #builder_struct_name <#(#builder_struct_generic_producers),*> {
-            #(pub #builder_struct_fields),*
+        #visibility struct #builder_struct_name <#(#builder_struct_generic_producers),*> {
+            #(#builder_struct_fields,)*
         }
         impl<#(#builder_struct_generic_producers),*> #builder_struct_name <#(#builder_struct_generic_consumers),*> {
             #[doc=#build_fn_documentation]
-            pub fn try_build(self) -> ::core::result::Result<#struct_name <#(#generic_args),*>, Error_> {
+            pub fn try_build #build_fn_generics (self) -> ::core::result::Result<#struct_name <#(#generic_args),*>, Error_>
+            #build_fn_where_clause
+            {
                 #struct_name::try_new(
                     #(self.#builder_struct_field_names),*
                 )
             }
             #[doc=#build_or_recover_fn_documentation]
-            pub fn try_build_or_recover(self) -> ::core::result::Result<#struct_name <#(#generic_args),*>, (Error_, Heads<#(#generic_args),*>)> {
+            pub fn try_build_or_recover #build_fn_generics (self) -> ::core::result::Result<#struct_name <#(#generic_args),*>, (Error_, #heads_name<#(#generic_args),*>)>
+            #build_fn_where_clause
+            {
                 #struct_name::try_new_or_recover(
                     #(self.#builder_struct_field_names),*
                 )
@@ -762,25 +1603,27 @@ fn create_try_builder_and_constructor(
 
 fn make_with_functions(
     field_info: &[StructFieldInfo],
-    do_chain_hack: bool,
-    do_no_doc: bool,
+    options: &Options,
 ) -> Result<Vec<TokenStream2>, Error> {
     let mut users = Vec::new();
+    let bind_storage_ref = bind_storage(quote! { self }, false);
+    let bind_storage_mut = bind_storage(quote! { self }, true);
     for field in field_info {
-        let field_name = &field.name;
         let field_type = &field.typ;
         // If the field is not a tail, we need to serve up the same kind of reference that other
         // fields in the struct may have borrowed to ensure safety.
         if field.field_type == FieldType::Tail {
+            let field_ref = field.make_field_ref();
+            let field_mut_ref = field.make_field_mut_ref();
             let user_name = format_ident!("with_{}", &field.name);
             let documentation = format!(
                 concat!(
                     "Provides an immutable reference to `{0}`. This method was generated because ",
                     "`{0}` is a [tail field](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions)."
                 ),
-                field.name.to_string()
+                field.name
             );
-            let documentation = if !do_no_doc {
+            let documentation = if !options.do_no_doc {
                 quote! {
                     #[doc=#documentation]
                 }
@@ -793,7 +1636,8 @@ fn make_with_functions(
                     &'outer_borrow self,
                     user: impl for<'this> ::core::ops::FnOnce(&'outer_borrow #field_type) -> ReturnType,
                 ) -> ReturnType {
-                    user(&self. #field_name)
+                    #bind_storage_ref
+                    user(#field_ref)
                 }
             });
             // If it is not borrowed at all it's safe to allow mutably borrowing it.
@@ -803,9 +1647,9 @@ fn make_with_functions(
                     "Provides a mutable reference to `{0}`. This method was generated because ",
                     "`{0}` is a [tail field](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions)."
                 ),
-                field.name.to_string()
+                field.name
             );
-            let documentation = if !do_no_doc {
+            let documentation = if !options.do_no_doc {
                 quote! {
                     #[doc=#documentation]
                 }
@@ -818,33 +1662,67 @@ fn make_with_functions(
                     &'outer_borrow mut self,
                     user: impl for<'this> ::core::ops::FnOnce(&'outer_borrow mut #field_type) -> ReturnType,
                 ) -> ReturnType {
-                    user(&mut self. #field_name)
+                    #bind_storage_mut
+                    user(#field_mut_ref)
                 }
             });
         } else if field.field_type == FieldType::Borrowed {
-            let user_name = format_ident!("with_{}_contents", &field.name);
+            if field.contents_borrowed {
+                let user_name = format_ident!("with_{}_contents", &field.name);
+                let documentation = format!(
+                    concat!(
+                        "Provides limited immutable access to the contents of `{0}`. This method ",
+                        "was generated because `{0}` is immutably borrowed by other fields."
+                    ),
+                    field.name
+                );
+                let documentation = if !options.do_no_doc {
+                    quote! {
+                        #[doc=#documentation]
+                    }
+                } else {
+                    quote! { #[doc(hidden)] }
+                };
+                let contents_type = field.contents_ref_type(quote! { 'outer_borrow }, false);
+                let contents = field.make_contents_ref();
+                users.push(quote! {
+                    #documentation
+                    pub fn #user_name <'outer_borrow, ReturnType>(
+                        &'outer_borrow self,
+                        user: impl for<'this> ::core::ops::FnOnce(#contents_type) -> ReturnType,
+                    ) -> ReturnType {
+                        #bind_storage_ref
+                        user(#contents)
+                    }
+                });
+            }
+            // The field and its contents are only ever immutably borrowed, so handing out another
+            // immutable reference to the field itself is fine. StableDeref guarantees that the
+            // contents stay where they are while the container is shared.
+            let user_name = format_ident!("with_{}", &field.name);
             let documentation = format!(
                 concat!(
-                    "Provides limited immutable access to the contents of `{0}`. This method was ",
-                    "generated because `{0}` is immutably borrowed by other fields."
+                    "Provides an immutable reference to `{0}`. This method was generated ",
+                    "because `{0}` is only immutably borrowed by other fields."
                 ),
-                field.name.to_string()
+                field.name
             );
-            let documentation = if !do_no_doc {
+            let documentation = if !options.do_no_doc {
                 quote! {
                     #[doc=#documentation]
                 }
             } else {
                 quote! { #[doc(hidden)] }
             };
-            let content_type = deref_type(field_type, do_chain_hack)?;
+            let field_ref = field.make_field_ref();
             users.push(quote! {
                 #documentation
                 pub fn #user_name <'outer_borrow, ReturnType>(
                     &'outer_borrow self,
-                    user: impl for<'this> ::core::ops::FnOnce(&'outer_borrow #content_type) -> ReturnType,
+                    user: impl for<'this> ::core::ops::FnOnce(&'outer_borrow #field_type) -> ReturnType,
                 ) -> ReturnType {
-                    user(&*self. #field_name)
+                    #bind_storage_ref
+                    user(#field_ref)
                 }
             });
         } else if field.field_type == FieldType::BorrowedMut {
@@ -855,13 +1733,146 @@ fn make_with_functions(
     Ok(users)
 }
 
+/// Returns the indexes of all fields which borrow the field at `head_index`, either directly or
+/// through other fields, in construction order.
+fn transitive_dependents(field_info: &[StructFieldInfo], head_index: usize) -> Vec<usize> {
+    let mut affected = vec![false; field_info.len()];
+    affected[head_index] = true;
+    let mut dependents = Vec::new();
+    // Fields only borrow fields constructed before them, so a single pass is enough.
+    for (index, field) in field_info.iter().enumerate() {
+        if field.borrows.iter().any(|borrow| affected[borrow.index]) {
+            affected[index] = true;
+            dependents.push(index);
+        }
+    }
+    dependents
+}
+
+fn make_replace_functions(
+    field_info: &[StructFieldInfo],
+    generic_params: &Generics,
+    options: &Options,
+) -> Result<Vec<TokenStream2>, Error> {
+    let erased = erased_lifetime(generic_params);
+    let mut replacers = Vec::new();
+    for (head_index, head) in field_info.iter().enumerate() {
+        if head.field_type == FieldType::Tail || !head.borrows.is_empty() {
+            continue;
+        }
+        let head_name = &head.name;
+        let head_type = &head.typ;
+        let dependents = transitive_dependents(field_info, head_index);
+        let mut params = vec![quote! { #head_name: #head_type }];
+        let mut doc_table = format!("| `{}` | The new value of this field |\n", head_name);
+        // Builders are taken in the order the fields were declared in, like in the constructor.
+        for index in declaration_order(field_info) {
+            if !dependents.contains(&index) {
+                continue;
+            }
+            let field = &field_info[index];
+            let builder_name = field.builder_name();
+            if let ArgType::TraitBound(bound_type) = make_constructor_arg_type(field, field_info) {
+                params.push(quote! { #builder_name: impl #bound_type });
+            }
+            doc_table += &format!(
+                "| `{}` | Rebuilds `{}` from the new value of `{}` |\n",
+                builder_name, field.name, head_name
+            );
+        }
+        let mut drop_code = Vec::new();
+        let mut build_code = Vec::new();
+        for (index, field) in field_info.iter().enumerate() {
+            let field_name = &field.name;
+            if index == head_index {
+                let stored_value = field.make_stored_value(quote! { #head_name });
+                let old_value = field.make_unstored_value(quote! {
+                    ::core::mem::replace(&mut (*storage).#field_name, #stored_value)
+                });
+                build_code.push(quote! {
+                    let old_head = unsafe { #old_value };
+                });
+            } else if dependents.contains(&index) {
+                let builder_name = field.builder_name();
+                let builder_args = field.builder_args(field_info);
+                let stored_type = replace_this_with_lifetime(&field.stored_type(), &erased);
+                let stored_value = field.make_stored_value(quote! { #field_name });
+                drop_code.push(quote! {
+                    ::core::mem::ManuallyDrop::drop(&mut (*storage).#field_name);
+                });
+                build_code.push(quote! {
+                    let #field_name = #builder_name (#(#builder_args),*);
+                    unsafe {
+                        ((&mut (*storage).#field_name) as *mut #stored_type).write(#stored_value);
+                    }
+                });
+            }
+            // Only create references to fields which are used by a builder, other borrowers of
+            // the field still exist.
+            let used_by_builder = dependents.iter().any(|&dependent| {
+                field_info[dependent]
+                    .borrows
+                    .iter()
+                    .any(|borrow| borrow.index == index)
+            });
+            if !used_by_builder {
+                continue;
+            }
+            if field.field_type == FieldType::Borrowed {
+                build_code.push(field.make_illegal_static_reference());
+            } else if field.field_type == FieldType::BorrowedMut {
+                build_code.push(field.make_illegal_static_mut_reference());
+            }
+        }
+        // Dependents are dropped in reverse construction order, the same order the struct itself
+        // drops them in.
+        drop_code.reverse();
+
+        let user_name = format_ident!("replace_{}", head_name);
+        let documentation = format!(
+            concat!(
+                "Replaces the value of `{0}`, returning the old value. Every field which borrows ",
+                "`{0}`, directly or through other fields, is dropped beforehand and rebuilt ",
+                "afterwards using the provided functions. The process is aborted if any of them ",
+                "panics, since the struct cannot be left partially built. The arguments are as ",
+                "follows:\n\n| Argument | Suggested Use |\n| --- | --- |\n{1}"
+            ),
+            head_name, doc_table
+        );
+        let documentation = if !options.do_no_doc {
+            quote! {
+                #[doc=#documentation]
+            }
+        } else {
+            quote! { #[doc(hidden)] }
+        };
+        // The references given to the builders are created the same way the constructor creates
+        // them, through a raw pointer to the fields.
+        let bind_storage_ptr = bind_storage_ptr(quote! { (self as *mut Self) });
+        replacers.push(quote! {
+            #documentation
+            pub fn #user_name(&mut self, #(#params),*) -> #head_type {
+                #bind_storage_ptr
+                let abort_guard = ::ouroboros::macro_help::AbortOnUnwind;
+                unsafe {
+                    #(#drop_code)*
+                }
+                #(#build_code)*
+                ::core::mem::forget(abort_guard);
+                old_head
+            }
+        });
+    }
+    Ok(replacers)
+}
+
 fn make_with_all_function(
     struct_name: &Ident,
+    visibility: &syn::Visibility,
     field_info: &[StructFieldInfo],
     generic_params: &Generics,
     generic_args: &[TokenStream2],
-    do_chain_hack: bool,
-    do_no_doc: bool,
+    options: &Options,
 ) -> Result<(TokenStream2, TokenStream2), Error> {
     let mut fields = Vec::new();
     let mut field_assignments = Vec::new();
@@ -873,14 +1884,28 @@ fn make_with_all_function(
         let field_type = &field.typ;
         if field.field_type == FieldType::Tail {
             fields.push(quote! { pub #field_name: &'outer_borrow #field_type });
-            field_assignments.push(quote! { #field_name: &self.#field_name });
+            let field_ref = field.make_field_ref();
+            let field_mut_ref = field.make_field_mut_ref();
+            field_assignments.push(quote! { #field_name: #field_ref });
             mut_fields.push(quote! { pub #field_name: &'outer_borrow mut #field_type });
-            mut_field_assignments.push(quote! { #field_name: &mut self.#field_name });
+            mut_field_assignments.push(quote! { #field_name: #field_mut_ref });
         } else if field.field_type == FieldType::Borrowed {
-            let value_name = format_ident!("{}_contents", field_name);
-            let content_type = deref_type(field_type, do_chain_hack)?;
-            fields.push(quote! { pub #value_name: &'outer_borrow #content_type });
-            field_assignments.push(quote! { #value_name: &*self.#field_name });
+            if field.contents_borrowed {
+                let value_name = format_ident!("{}_contents", field_name);
+                let contents_type = field.contents_ref_type(quote! { 'outer_borrow }, false);
+                let contents = field.make_contents_ref();
+                fields.push(quote! { pub #value_name: #contents_type });
+                field_assignments.push(quote! { #value_name: #contents });
+                // Immutably borrowed fields are only ever shared, so they can be provided
+                // alongside mutable references to the tail fields.
+                mut_fields.push(quote! { pub #value_name: #contents_type });
+                mut_field_assignments.push(quote! { #value_name: #contents });
+            }
+            let field_ref = field.make_field_ref();
+            fields.push(quote! { pub #field_name: &'outer_borrow #field_type });
+            field_assignments.push(quote! { #field_name: #field_ref });
+            mut_fields.push(quote! { pub #field_name: &'outer_borrow #field_type });
+            mut_field_assignments.push(quote! { #field_name: #field_ref });
         } else if field.field_type == FieldType::BorrowedMut {
             // Add nothing because we cannot borrow something that has already been mutably
             // borrowed.
@@ -912,53 +1937,84 @@ fn make_with_all_function(
             "[tail and immutably borrowed fields](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions) in an instance of ",
             "[`{0}`]({0})."
         ),
-        struct_name.to_string()
+        struct_name
     );
     let mut_struct_documentation = format!(
         concat!(
             "A struct for holding mutable references to all ",
-            "[tail fields](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions) in an instance of ",
-            "[`{0}`]({0})."
+            "[tail fields](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions) and ",
+            "immutable references to all ",
+            "[immutably borrowed fields](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions) ",
+            "in an instance of [`{0}`]({0})."
         ),
-        struct_name.to_string()
+        struct_name
     );
+    // Generic parameters only used by mutably borrowed fields do not appear in these structs.
+    let visible_types = field_info
+        .iter()
+        .filter(|field| field.field_type != FieldType::BorrowedMut)
+        .map(|field| &field.typ);
+    if let Some(phantom_type) = phantom_type_for_unused_params(visible_types, generic_params) {
+        fields.push(quote! { #[doc(hidden)] pub _phantom: #phantom_type });
+        field_assignments.push(quote! { _phantom: ::core::marker::PhantomData });
+        mut_fields.push(quote! { #[doc(hidden)] pub _phantom: #phantom_type });
+        mut_field_assignments.push(quote! { _phantom: ::core::marker::PhantomData });
+    }
+    // 'this is always shorter than the lifetime it is replaced with, and thus than every lifetime
+    // outliving that one. Stating this lets code which receives these structs rely on it, which
+    // enums need when a variant does not use every generic parameter of the enum.
+    let this_bounds = this_outlives_bounds(generic_params);
+    let this_bound = if this_bounds.is_empty() {
+        quote! {}
+    } else {
+        quote! { where #(#this_bounds),* }
+    };
+    let borrowed_fields_name = borrowed_fields_name(struct_name);
+    let borrowed_mut_fields_name = borrowed_mut_fields_name(struct_name);
     let struct_defs = quote! {
         #[doc=#struct_documentation]
-        pub struct BorrowedFields #new_generic_params { #(#fields),* }
+        #visibility struct #borrowed_fields_name #new_generic_params #this_bound { #(#fields),* }
         #[doc=#mut_struct_documentation]
-        pub struct BorrowedMutFields #new_generic_params { #(#mut_fields),* }
+        #visibility struct #borrowed_mut_fields_name #new_generic_params #this_bound {
+            #(#mut_fields),*
+        }
     };
-    let borrowed_fields_type = quote! { BorrowedFields<#(#new_generic_args),*> };
-    let borrowed_mut_fields_type = quote! { BorrowedMutFields<#(#new_generic_args),*> };
+    let borrowed_fields_type = quote! { #borrowed_fields_name<#(#new_generic_args),*> };
+    let borrowed_mut_fields_type = quote! { #borrowed_mut_fields_name<#(#new_generic_args),*> };
     let documentation = concat!(
         "This method provides immutable references to all ",
         "[tail and immutably borrowed fields](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions).",
     );
     let mut_documentation = concat!(
         "This method provides mutable references to all ",
-        "[tail fields](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions).",
+        "[tail fields](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions) ",
+        "along with immutable references to all ",
+        "[immutably borrowed fields](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions).",
     );
-    let documentation = if !do_no_doc {
+    let documentation = if !options.do_no_doc {
         quote! {
             #[doc=#documentation]
         }
     } else {
         quote! { #[doc(hidden)] }
     };
-    let mut_documentation = if !do_no_doc {
+    let mut_documentation = if !options.do_no_doc {
         quote! {
             #[doc=#mut_documentation]
         }
     } else {
         quote! { #[doc(hidden)] }
     };
+    let bind_storage_ref = bind_storage(quote! { self }, false);
+    let bind_storage_mut = bind_storage(quote! { self }, true);
     let fn_defs = quote! {
         #documentation
         pub fn with <'outer_borrow, ReturnType>(
             &'outer_borrow self,
             user: impl for<'this> ::core::ops::FnOnce(#borrowed_fields_type) -> ReturnType
         ) -> ReturnType {
-            user(BorrowedFields {
+            #bind_storage_ref
+            user(#borrowed_fields_name {
                 #(#field_assignments),*
             })
         }
@@ -967,7 +2023,8 @@ fn make_with_all_function(
             &'outer_borrow mut self,
             user: impl for<'this> ::core::ops::FnOnce(#borrowed_mut_fields_type) -> ReturnType
         ) -> ReturnType {
-            user(BorrowedMutFields {
+            #bind_storage_mut
+            user(#borrowed_mut_fields_name {
                 #(#mut_field_assignments),*
             })
         }
@@ -975,29 +2032,102 @@ fn make_with_all_function(
     Ok((struct_defs, fn_defs))
 }
 
+/// Returns true if `ident` appears anywhere in `tokens`.
+fn tokens_mention(tokens: TokenStream2, ident: &Ident) -> bool {
+    tokens.into_iter().any(|token| match token {
+        TokenTree::Ident(other) => &other == ident,
+        TokenTree::Group(group) => tokens_mention(group.stream(), ident),
+        _ => false,
+    })
+}
+
+/// Generic parameters which are only used by self-referencing fields would be unused in the
+/// Heads struct. If there are any, this returns a `PhantomData` type which uses all of them.
+fn heads_phantom_type(
+    field_info: &[StructFieldInfo],
+    generic_params: &Generics,
+) -> Option<TokenStream2> {
+    let head_types = field_info
+        .iter()
+        .filter(|field| field.borrows.is_empty())
+        .map(|field| &field.typ);
+    phantom_type_for_unused_params(head_types, generic_params)
+}
+
+/// Returns a `PhantomData` type mentioning every generic parameter which is not used by any of
+/// `types`, or `None` if all of them are used.
+fn phantom_type_for_unused_params<'a>(
+    types: impl Iterator<Item = &'a Type>,
+    generic_params: &Generics,
+) -> Option<TokenStream2> {
+    let types: Vec<_> = types.collect();
+    let is_used = |ident: &Ident| {
+        types
+            .iter()
+            .any(|typ| tokens_mention(quote! { #typ }, ident))
+    };
+    let mut unused = Vec::new();
+    for param in &generic_params.params {
+        match param {
+            GenericParam::Type(typ) if !is_used(&typ.ident) => {
+                let ident = &typ.ident;
+                unused.push(quote! { #ident });
+            }
+            GenericParam::Lifetime(lt) if !is_used(&lt.lifetime.ident) => {
+                let lifetime = &lt.lifetime;
+                unused.push(quote! { &#lifetime () });
+            }
+            _ => (),
+        }
+    }
+    if unused.is_empty() {
+        None
+    } else {
+        Some(quote! { ::core::marker::PhantomData<(#(#unused,)*)> })
+    }
+}
+
 /// Returns the Heads struct and a function to convert the original struct into a Heads instance.
 fn make_into_heads(
     struct_name: &Ident,
+    visibility: &syn::Visibility,
     field_info: &[StructFieldInfo],
     generic_params: &Generics,
     generic_args: &[TokenStream2],
-    do_no_doc: bool,
+    options: &Options,
 ) -> (TokenStream2, TokenStream2) {
-    let mut code = Vec::new();
+    // The struct implements Drop, so fields are read out of it instead of being moved. The
+    // on_drop function still runs since the struct is being torn down. Freeing the box of a head
+    // which is borrowed as a whole is fine even though the by-value `self` still contains a
+    // reference to it, since references inside a `ManuallyDrop` are not required to stay valid
+    // for the duration of the call.
+    let on_drop_call = make_on_drop_call(quote! { this }, options);
+    let heads_name = heads_name(struct_name);
+    let bind_storage = bind_storage(quote! { this }, true);
+    let mut code = vec![quote! {
+        let mut this = ::core::mem::ManuallyDrop::new(self);
+        #on_drop_call
+        #bind_storage
+    }];
     let mut field_names = Vec::new();
     let mut head_fields = Vec::new();
-    // Drop everything in the reverse order of what it was declared in. Fields that come later
-    // are only dependent on fields that came before them.
+    // Drop everything in the reverse order of what it was constructed in. Fields are only
+    // dependent on fields constructed before them.
     for field in field_info.iter().rev() {
         let field_name = &field.name;
         if field.borrows.is_empty() {
-            code.push(quote! { let #field_name = self.#field_name; });
+            let value = field.make_unstored_value(quote! {
+                ::core::ptr::read(&storage.#field_name)
+            });
+            code.push(quote! { let #field_name = unsafe { #value }; });
             field_names.push(field_name);
             let field_type = &field.typ;
             head_fields.push(quote! { pub #field_name: #field_type });
         } else {
             // Heads are fields that do not borrow anything.
-            code.push(quote! { ::core::mem::drop(self.#field_name); });
+            code.push(quote! {
+                unsafe { ::core::mem::ManuallyDrop::drop(&mut storage.#field_name) };
+            });
         }
     }
     let documentation = format!(
@@ -1005,12 +2135,20 @@ fn make_into_heads(
             "A struct which contains only the ",
             "[head fields](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions) of [`{0}`]({0})."
         ),
-        struct_name.to_string()
+        struct_name
     );
+    let (phantom_field, phantom_init) = match heads_phantom_type(field_info, generic_params) {
+        Some(phantom_type) => (
+            quote! { #[doc(hidden)] pub _phantom: #phantom_type, },
+            quote! { _phantom: ::core::marker::PhantomData, },
+        ),
+        None => (quote! {}, quote! {}),
+    };
     let heads_struct_def = quote! {
         #[doc=#documentation]
-        pub struct Heads #generic_params {
-            #(#head_fields),*
+        #visibility struct #heads_name #generic_params {
+            #(#head_fields,)*
+            #phantom_field
         }
     };
     let documentation = concat!(
@@ -1018,7 +2156,7 @@ fn make_into_heads(
         "[head fields](https://docs.rs/ouroboros/latest/ouroboros/attr.self_referencing.html#definitions) of this struct."
     ).to_owned();
 
-    let documentation = if !do_no_doc {
+    let documentation = if !options.do_no_doc {
         quote! {
             #[doc=#documentation]
         }
@@ -1028,25 +2166,755 @@ fn make_into_heads(
 
     let into_heads_fn = quote! {
         #documentation
-        #[allow(clippy::drop_ref)]
-        #[allow(clippy::drop_copy)]
-        pub fn into_heads(self) -> Heads<#(#generic_args),*> {
+        pub fn into_heads(self) -> #heads_name<#(#generic_args),*> {
             #(#code)*
-            Heads {
-                #(#field_names),*
+            #heads_name {
+                #(#field_names,)*
+                #phantom_init
             }
         }
     };
     (heads_struct_def, into_heads_fn)
 }
 
+/// Returns a wrapper which stores the struct in a `Mutex`, with a `_locked` version of each
+/// accessor that locks the mutex for exactly as long as the provided function runs.
+fn make_sync_wrapper(
+    struct_name: &Ident,
+    visibility: &syn::Visibility,
+    wrapper_name: &Ident,
+    field_info: &[StructFieldInfo],
+    generic_params: &Generics,
+    generic_args: &[TokenStream2],
+    options: &Options,
+) -> TokenStream2 {
+    let struct_type = quote! { #struct_name <#(#generic_args),*> };
+    let borrowed_fields_name = borrowed_fields_name(struct_name);
+    let borrowed_mut_fields_name = borrowed_mut_fields_name(struct_name);
+    let with_all_args = {
+        let mut args = Vec::from(generic_args);
+        args.insert(0, quote! { 'this });
+        args.insert(0, quote! { 'outer_borrow });
+        args
+    };
+    // Each entry is the name of the original accessor, whether it requires mutable access and the
+    // type of the argument it passes to the user.
+    let mut accessors = Vec::new();
+    for field in field_info {
+        let field_name = &field.name;
+        let field_type = &field.typ;
+        if field.field_type == FieldType::Tail {
+            accessors.push((
+                format_ident!("with_{}", field_name),
+                false,
+                quote! { &'outer_borrow #field_type },
+            ));
+            accessors.push((
+                format_ident!("with_{}_mut", field_name),
+                true,
+                quote! { &'outer_borrow mut #field_type },
+            ));
+        } else if field.field_type == FieldType::Borrowed {
+            if field.contents_borrowed {
+                accessors.push((
+                    format_ident!("with_{}_contents", field_name),
+                    false,
+                    field.contents_ref_type(quote! { 'outer_borrow }, false),
+                ));
+            }
+            accessors.push((
+                format_ident!("with_{}", field_name),
+                false,
+                quote! { &'outer_borrow #field_type },
+            ));
+        }
+    }
+    accessors.push((
+        format_ident!("with"),
+        false,
+        quote! { #borrowed_fields_name<#(#with_all_args),*> },
+    ));
+    accessors.push((
+        format_ident!("with_mut"),
+        true,
+        quote! { #borrowed_mut_fields_name<#(#with_all_args),*> },
+    ));
+
+    let mut users = Vec::new();
+    for (user_name, mutable, arg_type) in accessors {
+        let locked_name = format_ident!("{}_locked", user_name);
+        let documentation = format!(
+            concat!(
+                "Locks the mutex and calls [`{0}::{1}()`]({0}::{1}) on the wrapped value, ",
+                "releasing the lock once `user` returns."
+            ),
+            struct_name, user_name
+        );
+        let documentation = if !options.do_no_doc {
+            quote! {
+                #[doc=#documentation]
+            }
+        } else {
+            quote! { #[doc(hidden)] }
+        };
+        let guard = if mutable {
+            quote! { mut guard }
+        } else {
+            quote! { guard }
+        };
+        users.push(quote! {
+            #documentation
+            pub fn #locked_name <ReturnType>(
+                &self,
+                user: impl for<'outer_borrow, 'this> ::core::ops::FnOnce(#arg_type) -> ReturnType,
+            ) -> ReturnType {
+                let #guard = self.lock();
+                guard.#user_name(user)
+            }
+        });
+    }
+
+    let documentation = format!(
+        concat!(
+            "Stores an instance of [`{0}`]({0}) in a `Mutex` so that it can be shared between ",
+            "threads. Every accessor of `{0}` has a `_locked` version here which holds the lock ",
+            "only while the provided function runs. The wrapped value is always left in a valid ",
+            "state, so the lock ignores poisoning."
+        ),
+        struct_name
+    );
+    let documentation = if !options.do_no_doc {
+        quote! {
+            #[doc=#documentation]
+        }
+    } else {
+        quote! { #[doc(hidden)] }
+    };
+    quote! {
+        #documentation
+        #visibility struct #wrapper_name #generic_params {
+            inner: ::ouroboros::macro_help::Mutex<#struct_type>,
+        }
+        impl #generic_params #wrapper_name <#(#generic_args),*> {
+            /// Wraps the provided value in a new mutex.
+            pub fn new(value: #struct_type) -> Self {
+                Self {
+                    inner: ::ouroboros::macro_help::Mutex::new(value),
+                }
+            }
+            /// Locks the mutex, blocking until it is available. The guard can be used to call
+            /// multiple accessors without releasing the lock in between.
+            pub fn lock(&self) -> ::ouroboros::macro_help::MutexGuard<'_, #struct_type> {
+                self.inner
+                    .lock()
+                    .unwrap_or_else(::ouroboros::macro_help::PoisonError::into_inner)
+            }
+            /// Returns a mutable reference to the wrapped value. No locking is needed because
+            /// this requires exclusive access to the wrapper.
+            pub fn get_mut(&mut self) -> &mut #struct_type {
+                self.inner
+                    .get_mut()
+                    .unwrap_or_else(::ouroboros::macro_help::PoisonError::into_inner)
+            }
+            /// Consumes the wrapper and returns the wrapped value.
+            pub fn into_inner(self) -> #struct_type {
+                self.inner
+                    .into_inner()
+                    .unwrap_or_else(::ouroboros::macro_help::PoisonError::into_inner)
+            }
+            #(#users)*
+        }
+        impl #generic_params ::core::convert::From<#struct_type> for #wrapper_name <#(#generic_args),*> {
+            fn from(value: #struct_type) -> Self {
+                Self::new(value)
+            }
+        }
+    }
+}
+
+/// Implements the traits listed in `#[ouroboros(delegate(...))]` for the struct by forwarding
+/// them to the field they were placed on. Fields are accessed through `with_FIELD` and
+/// `with_FIELD_mut` so nothing returned by the trait's methods can borrow from the struct.
+fn make_delegated_impls(
+    struct_name: &Ident,
+    field_info: &[StructFieldInfo],
+    generic_params: &Generics,
+    generic_args: &[TokenStream2],
+) -> Result<TokenStream2, Error> {
+    let erased = erased_lifetime(generic_params);
+    let mut delegated = Vec::<String>::new();
+    let mut impls = Vec::new();
+    let mut errors = Errors::default();
+    for field in field_info {
+        for (to_delegate, span) in &field.options.delegate {
+            let span = *span;
+            if delegated.contains(&to_delegate.name()) {
+                errors.push(Error::new(
+                    span,
+                    format!(
+                        "`{}` is already delegated to another field.",
+                        to_delegate.name()
+                    ),
+                ));
+                continue;
+            }
+            delegated.push(to_delegate.name());
+            if to_delegate.needs_mut() && field.field_type != FieldType::Tail {
+                errors.push(Error::new(
+                    span,
+                    format!(
+                        concat!(
+                            "`{}` can only be delegated to tail fields, but `{}` is borrowed by ",
+                            "other fields."
+                        ),
+                        to_delegate.name(),
+                        field.name
+                    ),
+                ));
+                continue;
+            } else if field.field_type == FieldType::BorrowedMut {
+                errors.push(Error::new(
+                    span,
+                    format!(
+                        concat!(
+                            "`{}` can only be delegated to tail fields or immutably borrowed ",
+                            "fields, but `{}` is mutably borrowed."
+                        ),
+                        to_delegate.name(),
+                        field.name
+                    ),
+                ));
+                continue;
+            }
+
+            let user = format_ident!("with_{}", field.name);
+            let user_mut = format_ident!("with_{}_mut", field.name);
+            let user_pin_mut = format_ident!("with_{}_pin_mut", field.name);
+            let field_type = &field.typ;
+            let (trait_path, body) = match to_delegate {
+                DelegatedTrait::Iterator => {
+                    let erased_field_type = replace_this_with_lifetime(field_type, &erased);
+                    (
+                        quote! { ::core::iter::Iterator },
+                        quote! {
+                            type Item = <#erased_field_type as ::core::iter::Iterator>::Item;
+
+                            fn next(&mut self) -> ::core::option::Option<Self::Item> {
+                                self.#user_mut(|field| ::core::iter::Iterator::next(field))
+                            }
+
+                            fn size_hint(&self) -> (usize, ::core::option::Option<usize>) {
+                                self.#user(|field| ::core::iter::Iterator::size_hint(field))
+                            }
+                        },
+                    )
+                }
+                DelegatedTrait::DoubleEndedIterator => (
+                    quote! { ::core::iter::DoubleEndedIterator },
+                    quote! {
+                        fn next_back(&mut self) -> ::core::option::Option<Self::Item> {
+                            self.#user_mut(|field| {
+                                ::core::iter::DoubleEndedIterator::next_back(field)
+                            })
+                        }
+                    },
+                ),
+                DelegatedTrait::ExactSizeIterator => (
+                    quote! { ::core::iter::ExactSizeIterator },
+                    quote! {
+                        fn len(&self) -> usize {
+                            self.#user(|field| ::core::iter::ExactSizeIterator::len(field))
+                        }
+                    },
+                ),
+                DelegatedTrait::Read => (
+                    quote! { ::ouroboros::macro_help::io::Read },
+                    quote! {
+                        fn read(&mut self, buf: &mut [u8]) -> ::ouroboros::macro_help::io::Result<usize> {
+                            self.#user_mut(|field| ::ouroboros::macro_help::io::Read::read(field, buf))
+                        }
+                    },
+                ),
+                DelegatedTrait::BufRead => (
+                    quote! { ::ouroboros::macro_help::io::BufRead },
+                    quote! {
+                        fn fill_buf(&mut self) -> ::ouroboros::macro_help::io::Result<&[u8]> {
+                            self.#user_mut(|field| ::ouroboros::macro_help::io::BufRead::fill_buf(field))
+                        }
+
+                        fn consume(&mut self, amt: usize) {
+                            self.#user_mut(|field| ::ouroboros::macro_help::io::BufRead::consume(field, amt))
+                        }
+                    },
+                ),
+                DelegatedTrait::Write => (
+                    quote! { ::ouroboros::macro_help::io::Write },
+                    quote! {
+                        fn write(&mut self, buf: &[u8]) -> ::ouroboros::macro_help::io::Result<usize> {
+                            self.#user_mut(|field| ::ouroboros::macro_help::io::Write::write(field, buf))
+                        }
+
+                        fn flush(&mut self) -> ::ouroboros::macro_help::io::Result<()> {
+                            self.#user_mut(|field| ::ouroboros::macro_help::io::Write::flush(field))
+                        }
+                    },
+                ),
+                DelegatedTrait::Display => (
+                    quote! { ::core::fmt::Display },
+                    quote! {
+                        fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
+                            self.#user(|field| ::core::fmt::Display::fmt(field, f))
+                        }
+                    },
+                ),
+                DelegatedTrait::Future => {
+                    let erased_field_type = replace_this_with_lifetime(field_type, &erased);
+                    (
+                        quote! { ::core::future::Future },
+                        quote! {
+                            type Output = <#erased_field_type as ::core::future::Future>::Output;
+
+                            fn poll(
+                                self: ::core::pin::Pin<&mut Self>,
+                                cx: &mut ::core::task::Context<'_>,
+                            ) -> ::core::task::Poll<Self::Output> {
+                                self.#user_pin_mut(|field| ::core::future::Future::poll(field, cx))
+                            }
+                        },
+                    )
+                }
+                DelegatedTrait::Stream => {
+                    let erased_field_type = replace_this_with_lifetime(field_type, &erased);
+                    (
+                        quote! { ::ouroboros::macro_help::Stream },
+                        quote! {
+                            type Item = <#erased_field_type as ::ouroboros::macro_help::Stream>::Item;
+
+                            fn poll_next(
+                                self: ::core::pin::Pin<&mut Self>,
+                                cx: &mut ::core::task::Context<'_>,
+                            ) -> ::core::task::Poll<::core::option::Option<Self::Item>> {
+                                self.#user_pin_mut(|field| {
+                                    ::ouroboros::macro_help::Stream::poll_next(field, cx)
+                                })
+                            }
+
+                            fn size_hint(&self) -> (usize, ::core::option::Option<usize>) {
+                                self.#user(|field| ::ouroboros::macro_help::Stream::size_hint(field))
+                            }
+                        },
+                    )
+                }
+                DelegatedTrait::Debug => (
+                    quote! { ::core::fmt::Debug },
+                    quote! {
+                        fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
+                            self.#user(|field| ::core::fmt::Debug::fmt(field, f))
+                        }
+                    },
+                ),
+                DelegatedTrait::Listed { path, methods } => {
+                    let methods = methods
+                        .iter()
+                        .map(|method| make_forwarding_method(method, path, &user, &user_mut));
+                    (quote! { #path }, quote! { #(#methods)* })
+                }
+            };
+            impls.push(quote! {
+                impl #generic_params #trait_path for #struct_name <#(#generic_args),*> {
+                    #body
+                }
+            });
+        }
+    }
+    errors.finish()?;
+    Ok(quote! { #(#impls)* })
+}
+
+/// Implements a method listed in `delegate(MyTrait { ... })` by calling the trait's method on the
+/// field. Arguments are renamed since their patterns may not be usable as expressions.
+fn make_forwarding_method(
+    method: &syn::TraitItemMethod,
+    trait_path: &syn::Path,
+    user: &Ident,
+    user_mut: &Ident,
+) -> TokenStream2 {
+    let attrs = &method.attrs;
+    let mut sig = method.sig.clone();
+    let mut accessor = user;
+    let mut args = Vec::new();
+    for (index, input) in sig.inputs.iter_mut().enumerate() {
+        match input {
+            syn::FnArg::Receiver(receiver) => {
+                if receiver.mutability.is_some() {
+                    accessor = user_mut;
+                }
+            }
+            syn::FnArg::Typed(arg) => {
+                let name = format_ident!("arg_{}", index);
+                *arg.pat = syn::parse_quote! { #name };
+                args.push(name);
+            }
+        }
+    }
+    let method_name = &sig.ident;
+    quote! {
+        #(#attrs)*
+        #sig {
+            self.#accessor(|field| #trait_path::#method_name(field, #(#args),*))
+        }
+    }
+}
+
+/// Returns code which passes the fields of `target` to the function given by the `on_drop`
+/// option, if there is one.
+fn make_on_drop_call(target: TokenStream2, options: &Options) -> TokenStream2 {
+    match &options.on_drop {
+        Some(on_drop) => quote! { #target.with_mut(|fields| #on_drop(fields)); },
+        None => quote! {},
+    }
+}
+
+/// Implements `Drop` for the struct, dropping every field before the fields it borrows. The
+/// fields are stored in `ManuallyDrop` so that they can be kept in declaration order. Since this
+/// prevents users from implementing `Drop` themselves, the `on_drop` option runs their code first.
+fn make_drop_impl(
+    struct_name: &Ident,
+    field_info: &[StructFieldInfo],
+    generic_params: &Generics,
+    generic_args: &[TokenStream2],
+    options: &Options,
+) -> TokenStream2 {
+    let where_clause = &generic_params.where_clause;
+    let field_names = field_info.iter().rev().map(|field| &field.name);
+    let on_drop_call = make_on_drop_call(quote! { self }, options);
+    let bind_storage = bind_storage(quote! { self }, true);
+    quote! {
+        impl #generic_params ::core::ops::Drop for #struct_name <#(#generic_args),*>
+        #where_clause
+        {
+            fn drop(&mut self) {
+                #on_drop_call
+                #bind_storage
+                // SAFETY: Fields are only dropped here, in the reverse of the order they were
+                // constructed in, so no field is dropped while another field borrows it.
+                unsafe {
+                    #(::core::mem::ManuallyDrop::drop(&mut storage.#field_names);)*
+                }
+            }
+        }
+    }
+}
+
+/// Removes the `#[derive(...)]` attributes from the struct, since derived implementations would see
+/// the fields as they are stored rather than as they were declared. `Debug` is generated by
+/// [make_debug_impl] instead, so where it was derived is returned. Any other derive is an error.
+fn take_derives(struct_def: &mut ItemStruct) -> Result<Option<Span>, Error> {
+    let mut debug = None;
+    let mut errors = Errors::default();
+    let mut remaining_attrs = Vec::new();
+    for attr in struct_def.attrs.drain(..) {
+        if !parse::attr_is(&attr, "derive") {
+            remaining_attrs.push(attr);
+            continue;
+        }
+        let derived = match attr.parse_args_with(
+            syn::punctuated::Punctuated::<syn::Path, syn::Token![,]>::parse_terminated,
+        ) {
+            Ok(derived) => derived,
+            Err(err) => {
+                errors.push(err);
+                continue;
+            }
+        };
+        for path in derived {
+            if matches!(DelegatedTrait::from_path(&path), Ok(DelegatedTrait::Debug)) {
+                debug = Some(syn::spanned::Spanned::span(&path));
+            } else {
+                errors.push(Error::new_spanned(
+                    &path,
+                    concat!(
+                        "Only `Debug` can be derived for self-referencing structs, other derives ",
+                        "would see the fields as they are stored rather than as they were declared."
+                    ),
+                ));
+            }
+        }
+    }
+    struct_def.attrs = remaining_attrs;
+    errors.finish()?;
+    Ok(debug)
+}
+
+/// Implements `Debug` in place of `#[derive(Debug)]`, printing the fields under their declared
+/// names and in their declared order. Mutably borrowed fields cannot be accessed, so they are
+/// left out.
+fn make_debug_impl(
+    struct_name: &Ident,
+    field_info: &[StructFieldInfo],
+    generic_params: &Generics,
+    generic_args: &[TokenStream2],
+    derive_span: Span,
+) -> Result<TokenStream2, Error> {
+    for field in field_info {
+        if let Some((_, span)) = field
+            .options
+            .delegate
+            .iter()
+            .find(|(to_delegate, _)| matches!(to_delegate, DelegatedTrait::Debug))
+        {
+            return Err(Error::new(
+                *span,
+                "`Debug` cannot be delegated to a field since it is derived for the struct.",
+            ));
+        }
+    }
+    let mut debug_generics = generic_params.clone();
+    let where_clause = debug_generics.make_where_clause();
+    for param in generic_params.type_params() {
+        let ident = &param.ident;
+        where_clause
+            .predicates
+            .push(syn::parse_quote! { #ident: ::core::fmt::Debug });
+    }
+    let mut fields = Vec::new();
+    let mut non_exhaustive = false;
+    for index in declaration_order(field_info) {
+        let field = &field_info[index];
+        let field_name = &field.name;
+        if field.field_type == FieldType::BorrowedMut {
+            non_exhaustive = true;
+        } else {
+            let name = field_name.to_string();
+            fields.push(quote! { .field(#name, fields.#field_name) });
+        }
+    }
+    let finish = if non_exhaustive {
+        quote! { finish_non_exhaustive }
+    } else {
+        quote! { finish }
+    };
+    let name = struct_name.to_string();
+    Ok(quote_spanned! { derive_span =>
+        impl #generic_params ::core::fmt::Debug for #struct_name <#(#generic_args),*>
+        #where_clause
+        {
+            fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
+                self.with(|fields| f.debug_struct(#name) #(#fields)* .#finish())
+            }
+        }
+    })
+}
+
+/// Returns true if the struct is declared with `#[repr(C)]`.
+fn has_repr_c(attrs: &[syn::Attribute]) -> bool {
+    attrs
+        .iter()
+        .filter(|attr| parse::attr_is(attr, "repr"))
+        .any(|attr| {
+            match attr.parse_meta() {
+            Ok(syn::Meta::List(list)) => list.nested.iter().any(|nested| {
+                matches!(nested, syn::NestedMeta::Meta(syn::Meta::Path(path)) if path.is_ident("C"))
+            }),
+            _ => false,
+        }
+        })
+}
+
+/// Generates `FIELD_ptr` for every field of a `#[repr(C)]` struct, along with `FIELD_mut_ptr` for
+/// tail fields, so that the struct and its fields can be handed to foreign code.
+fn make_raw_pointer_accessors(
+    field_info: &[StructFieldInfo],
+    generic_params: &Generics,
+    options: &Options,
+) -> TokenStream2 {
+    let erased = erased_lifetime(generic_params);
+    let bind_storage_ref = bind_storage(quote! { self }, false);
+    let bind_storage_mut = bind_storage(quote! { self }, true);
+    let mut accessors = Vec::new();
+    for index in declaration_order(field_info) {
+        let field = &field_info[index];
+        let field_name = &field.name;
+        let field_type = replace_this_with_lifetime(&field.typ, &erased);
+        let place = field.make_field_place(quote! { storage });
+        let accessor_name = format_ident!("{}_ptr", field_name);
+        let documentation = format!(
+            concat!(
+                "Returns a raw pointer to `{0}`. Fields are kept in the order they were declared ",
+                "in, so this points into the struct itself unless `{0}` is borrowed as a whole, ",
+                "in which case it is stored on the heap. The pointer must not be used to modify ",
+                "`{0}`, and references derived from it must not outlive the struct."
+            ),
+            field_name
+        );
+        let documentation = if !options.do_no_doc {
+            quote! { #[doc=#documentation] }
+        } else {
+            quote! { #[doc(hidden)] }
+        };
+        accessors.push(quote! {
+            #documentation
+            pub fn #accessor_name(&self) -> *const #field_type {
+                #bind_storage_ref
+                &#place as *const #field_type
+            }
+        });
+        if field.field_type != FieldType::Tail {
+            continue;
+        }
+        let accessor_name = format_ident!("{}_mut_ptr", field_name);
+        let documentation = format!(
+            concat!(
+                "Returns a mutable raw pointer to `{0}`. This method was generated because the ",
+                "struct is `#[repr(C)]` and `{0}` is a tail field. References derived from the ",
+                "pointer must not outlive the struct."
+            ),
+            field_name
+        );
+        let documentation = if !options.do_no_doc {
+            quote! { #[doc=#documentation] }
+        } else {
+            quote! { #[doc(hidden)] }
+        };
+        accessors.push(quote! {
+            #documentation
+            pub fn #accessor_name(&mut self) -> *mut #field_type {
+                #bind_storage_mut
+                &mut #place as *mut #field_type
+            }
+        });
+    }
+    quote! { #(#accessors)* }
+}
+
+/// Generates `with_FIELD_pin_mut` for every field marked with `#[ouroboros(pin)]` or delegating a
+/// trait which requires pinning. Following the usual rules for structural pinning, the struct
+/// only implements `Unpin` if the pinned fields do. Users cannot implement `Drop` themselves,
+/// which could move out of a pinned field, since the Drop implementation from make_drop_impl
+/// would conflict with it. Returns items to place next to the struct and methods to place in its
+/// impl block.
+fn make_pin_projections(
+    struct_name: &Ident,
+    field_info: &[StructFieldInfo],
+    generic_params: &Generics,
+    generic_args: &[TokenStream2],
+    options: &Options,
+) -> Result<(TokenStream2, TokenStream2), Error> {
+    let mut pinned_types = Vec::new();
+    let mut users = Vec::new();
+    let mut errors = Errors::default();
+    for field in field_info {
+        let span = if let Some(span) = field.options.pin_span() {
+            span
+        } else {
+            continue;
+        };
+        if field.field_type != FieldType::Tail {
+            errors.push(Error::new(
+                span,
+                format!(
+                    "Only tail fields can be pinned, but `{}` is borrowed by other fields.",
+                    field.name
+                ),
+            ));
+            continue;
+        }
+        if options.on_drop.is_some() {
+            errors.push(Error::new(
+                span,
+                format!(
+                    concat!(
+                        "`{}` cannot be pinned since the struct uses `on_drop`, which receives ",
+                        "a mutable reference to it and could move out of it."
+                    ),
+                    field.name
+                ),
+            ));
+            continue;
+        }
+        let field_name = &field.name;
+        let field_type = &field.typ;
+        pinned_types.push(field_type);
+        let user_name = format_ident!("with_{}_pin_mut", field_name);
+        let documentation = format!(
+            concat!(
+                "Provides a pinned mutable reference to `{0}`. This method was generated because ",
+                "`{0}` is structurally pinned, so pinning this struct also pins `{0}`."
+            ),
+            field_name
+        );
+        let documentation = if !options.do_no_doc {
+            quote! {
+                #[doc=#documentation]
+            }
+        } else {
+            quote! { #[doc(hidden)] }
+        };
+        users.push(quote! {
+            #documentation
+            pub fn #user_name <'outer_borrow, ReturnType>(
+                self: ::core::pin::Pin<&'outer_borrow mut Self>,
+                user: impl for<'this> ::core::ops::FnOnce(
+                    ::core::pin::Pin<&'outer_borrow mut #field_type>
+                ) -> ReturnType,
+            ) -> ReturnType {
+                // SAFETY: The field is never moved while the struct is pinned. The generated Drop
+                // implementation drops it in place, and the struct only implements Unpin if all
+                // pinned fields do.
+                unsafe {
+                    let this = ::core::pin::Pin::get_unchecked_mut(self);
+                    let storage = ::ouroboros::macro_help::Hidden::get_mut(&mut this.__ouroboros_fields);
+                    user(::core::pin::Pin::new_unchecked(&mut *storage.#field_name))
+                }
+            }
+        });
+    }
+    errors.finish()?;
+    if pinned_types.is_empty() {
+        return Ok((quote! {}, quote! {}));
+    }
+    let items = quote! {
+        impl #generic_params ::core::marker::Unpin for #struct_name <#(#generic_args),*>
+        where
+            #(for<'this> #pinned_types: ::core::marker::Unpin),*
+        {
+        }
+    };
+    Ok((items, quote! { #(#users)* }))
+}
+
+/// `chain_hack` is no longer necessary, chained borrows work with any `StableDeref` container.
+/// Proc macros cannot emit warnings directly on stable, so this generates a use of a deprecated
+/// item positioned at the option instead. The warning is a separate item, so any `#[allow]`
+/// attributes on the struct are copied onto it to let them silence it as well.
+fn make_chain_hack_warning(options: &Options, item_attrs: &[syn::Attribute]) -> TokenStream2 {
+    if let Some(span) = options.chain_hack {
+        let allows = item_attrs.iter().filter(|attr| attr.path.is_ident("allow"));
+        let warning = make_warning(
+            span,
+            "chain_hack",
+            "chain_hack is no longer required and has no effect, it can be removed.",
+        );
+        quote! {
+            #(#allows)*
+            #warning
+        }
+    } else {
+        quote! {}
+    }
+}
+
+/// Generates the self-referencing struct and everything that goes with it. The information about
+/// its fields is returned as well, so that enums can build their own methods on top of it.
 fn self_referencing_impl(
-    original_struct_def: ItemStruct,
-    do_chain_hack: bool,
-    do_no_doc: bool,
-) -> Result<TokenStream, Error> {
+    mut original_struct_def: ItemStruct,
+    options: &Options,
+) -> Result<(TokenStream2, Vec<StructFieldInfo>), Error> {
+    if let Some(lifetime) = &options.lifetime {
+        rename_internal_lifetime(&mut original_struct_def, lifetime)?;
+    }
+    let derive_debug = take_derives(&mut original_struct_def)?;
     let struct_name = &original_struct_def.ident;
-    let mod_name = format_ident!("ouroboros_impl_{}", struct_name.to_string().to_snake_case());
     let visibility = &original_struct_def.vis;
 
     let (actual_struct_def, field_info) = create_actual_struct(&original_struct_def)?;
@@ -1056,110 +2924,226 @@ fn self_referencing_impl(
 
     let builder_struct_name = format_ident!("{}Builder", struct_name);
     let (builder_def, constructor_def) = create_builder_and_constructor(
-        &struct_name,
+        struct_name,
+        visibility,
         &builder_struct_name,
         &generic_params,
         &generic_args,
         &field_info[..],
-        do_chain_hack,
-        do_no_doc,
+        options,
     )?;
     let try_builder_struct_name = format_ident!("{}TryBuilder", struct_name);
     let (try_builder_def, try_constructor_def) = create_try_builder_and_constructor(
-        &struct_name,
+        struct_name,
+        visibility,
         &try_builder_struct_name,
         &generic_params,
         &generic_args,
         &field_info[..],
-        do_chain_hack,
-        do_no_doc,
+        options,
     )?;
 
-    let users = make_with_functions(&field_info[..], do_chain_hack, do_no_doc)?;
+    let users = make_with_functions(&field_info[..], options)?;
+    let replacers = make_replace_functions(&field_info[..], &generic_params, options)?;
     let (with_all_struct_defs, with_all_fn_defs) = make_with_all_function(
         struct_name,
+        visibility,
         &field_info[..],
         &generic_params,
         &generic_args,
-        do_chain_hack,
-        do_no_doc,
+        options,
     )?;
     let (heads_struct_def, into_heads_fn) = make_into_heads(
         struct_name,
+        visibility,
         &field_info[..],
         &generic_params,
         &generic_args,
-        do_no_doc,
+        options,
     );
 
-    Ok(TokenStream::from(quote! {
-        mod #mod_name {
-            use super::*;
-            #actual_struct_def
-            #builder_def
-            #try_builder_def
-            #with_all_struct_defs
-            #heads_struct_def
-            impl #generic_params #struct_name <#(#generic_args),*> {
-                #constructor_def
-                #try_constructor_def
-                #(#users)*
-                #with_all_fn_defs
-                #into_heads_fn
-            }
+    // These check how field options are combined, so both run before reporting anything.
+    let mut errors = Errors::default();
+    let delegated_impls = errors.record(make_delegated_impls(
+        struct_name,
+        &field_info[..],
+        &generic_params,
+        &generic_args,
+    ));
+    let pin_projections = errors.record(make_pin_projections(
+        struct_name,
+        &field_info[..],
+        &generic_params,
+        &generic_args,
+        options,
+    ));
+    let debug_impl = match derive_debug {
+        Some(span) => errors.record(make_debug_impl(
+            struct_name,
+            &field_info[..],
+            &generic_params,
+            &generic_args,
+            span,
+        )),
+        None => Some(quote! {}),
+    };
+    errors.finish()?;
+    let (delegated_impls, (pin_items, pin_users), debug_impl) = (
+        delegated_impls.unwrap(),
+        pin_projections.unwrap(),
+        debug_impl.unwrap(),
+    );
+
+    let drop_impl = make_drop_impl(
+        struct_name,
+        &field_info[..],
+        &generic_params,
+        &generic_args,
+        options,
+    );
+    let raw_pointer_accessors = if has_repr_c(&original_struct_def.attrs) {
+        make_raw_pointer_accessors(&field_info[..], &generic_params, options)
+    } else {
+        quote! {}
+    };
+
+    let sync_wrapper_def = if options.sync {
+        let wrapper_name = format_ident!("{}Mutex", struct_name);
+        make_sync_wrapper(
+            struct_name,
+            visibility,
+            &wrapper_name,
+            &field_info[..],
+            &generic_params,
+            &generic_args,
+            options,
+        )
+    } else {
+        quote! {}
+    };
+
+    // Everything is emitted in place, so the generated items are visible exactly where the
+    // original struct would have been.
+    let tokens = quote! {
+        #actual_struct_def
+        #builder_def
+        #try_builder_def
+        #with_all_struct_defs
+        #heads_struct_def
+        #sync_wrapper_def
+        #delegated_impls
+        #debug_impl
+        #pin_items
+        #drop_impl
+        impl #generic_params #struct_name <#(#generic_args),*> {
+            #constructor_def
+            #try_constructor_def
+            #(#users)*
+            #(#replacers)*
+            #pin_users
+            #raw_pointer_accessors
+            #with_all_fn_defs
+            #into_heads_fn
         }
-        #visibility use #mod_name :: #struct_name;
-        #visibility use #mod_name :: #builder_struct_name;
-        #visibility use #mod_name :: #try_builder_struct_name;
-    }))
+    };
+    Ok((tokens, field_info))
 }
 
-#[proc_macro_attribute]
-pub fn self_referencing(attr: TokenStream, item: TokenStream) -> TokenStream {
-    let mut do_chain_hack = false;
-    let mut do_no_doc = false;
-    let mut expecting_comma = false;
-    for token in <TokenStream as std::convert::Into<TokenStream2>>::into(attr).into_iter() {
-        if let TokenTree::Ident(ident) = &token {
-            if expecting_comma {
-                return Error::new(token.span(), "Unexpected identifier, expected comma.")
-                    .to_compile_error()
-                    .into();
-            }
-            match &ident.to_string()[..] {
-                "chain_hack" => do_chain_hack = true,
-                "no_doc" => do_no_doc = true,
-                _ => {
-                    return Error::new_spanned(
-                        &ident,
-                        "Unknown identifier, expected 'chain_hack' or 'no_doc'.",
-                    )
-                    .to_compile_error()
-                    .into()
+/// Produces a plain version of a struct or enum which could not be processed, with the macro's
+/// attributes removed and the internal lifetime erased. Emitting it next to the errors keeps code
+/// which uses the type from reporting a cascade of unrelated errors.
+fn make_fallback_definition(item: &Item, options: &Options) -> TokenStream2 {
+    let strip_attrs = |fields: &mut Fields| {
+        for field in fields.iter_mut() {
+            field.attrs.retain(|attr| {
+                !parse::attr_is(attr, "borrows") && !parse::attr_is(attr, "ouroboros")
+            });
+        }
+    };
+    // 'this is erased even when a custom name was chosen, in case both were used.
+    let mut internal_lifetimes = vec![format_ident!("this")];
+    internal_lifetimes.extend(
+        options
+            .lifetime
+            .as_ref()
+            .map(|lifetime| lifetime.ident.clone()),
+    );
+    match item {
+        Item::Struct(struct_def) => {
+            let mut struct_def = struct_def.clone();
+            strip_attrs(&mut struct_def.fields);
+            let erased = erased_lifetime(&struct_def.generics);
+            for internal_lifetime in &internal_lifetimes {
+                RenameLifetime {
+                    from: internal_lifetime,
+                    to: &erased,
                 }
+                .visit_item_struct_mut(&mut struct_def);
             }
-            expecting_comma = true;
-        } else if let TokenTree::Punct(punct) = &token {
-            if !expecting_comma {
-                return Error::new(token.span(), "Unexpected punctuation, expected identifier.")
-                    .to_compile_error()
-                    .into();
+            quote! {
+                #[allow(dead_code)]
+                #struct_def
             }
-            if punct.as_char() != ',' {
-                return Error::new(token.span(), "Unknown punctuation, expected comma.")
-                    .to_compile_error()
-                    .into();
+        }
+        Item::Enum(enum_def) => {
+            let mut enum_def = enum_def.clone();
+            for variant in &mut enum_def.variants {
+                strip_attrs(&mut variant.fields);
+            }
+            let erased = erased_lifetime(&enum_def.generics);
+            for internal_lifetime in &internal_lifetimes {
+                RenameLifetime {
+                    from: internal_lifetime,
+                    to: &erased,
+                }
+                .visit_item_enum_mut(&mut enum_def);
+            }
+            quote! {
+                #[allow(dead_code)]
+                #enum_def
             }
-            expecting_comma = false;
-        } else {
-            return Error::new(token.span(), "Unknown syntax, expected identifier.")
-                .to_compile_error()
-                .into();
         }
+        _ => quote! {},
     }
-    let original_struct_def: ItemStruct = syn::parse_macro_input!(item);
-    match self_referencing_impl(original_struct_def, do_chain_hack, do_no_doc) {
-        Ok(content) => content,
-        Err(err) => err.to_compile_error().into(),
+}
+
+#[proc_macro_attribute]
+pub fn self_referencing(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let option_items = syn::parse_macro_input!(attr with parse::parse_option_list);
+    let mut errors = Errors::default();
+    let options = Options::from_items(option_items, &mut errors);
+    let item: Item = syn::parse_macro_input!(item);
+    // Problems with the options are reported together with any found in the item itself.
+    let result = match item.clone() {
+        Item::Struct(original_struct_def) => {
+            self_referencing_impl(original_struct_def, &options).map(|(tokens, _)| tokens)
+        }
+        Item::Enum(original_enum_def) => {
+            enums::self_referencing_enum_impl(original_enum_def, &options)
+        }
+        _ => Err(Error::new(
+            Span::call_site(),
+            "#[self_referencing] can only be applied to structs and enums.",
+        )),
+    };
+    let content = errors.record(result);
+    if let Err(err) = errors.finish() {
+        let error = err.to_compile_error();
+        let fallback_definition = make_fallback_definition(&item, &options);
+        return TokenStream::from(quote! {
+            #error
+            #fallback_definition
+        });
     }
+    let content = content.expect("Errors are reported above.");
+    let item_attrs: &[syn::Attribute] = match &item {
+        Item::Struct(struct_def) => &struct_def.attrs,
+        Item::Enum(enum_def) => &enum_def.attrs,
+        _ => &[],
+    };
+    let chain_hack_warning = make_chain_hack_warning(&options, item_attrs);
+    TokenStream::from(quote! {
+        #chain_hack_warning
+        #content
+    })
 }
@@ -0,0 +1,542 @@
+//! Grammar for the options passed to `#[self_referencing(...)]`, the `#[borrows(...)]` attribute
+//! and the per-field `#[ouroboros(...)]` attribute.
+
+use crate::Errors;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{quote, ToTokens};
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
+use syn::{
+    token, Attribute, Error, FnArg, Ident, Lifetime, Lit, Path, PathArguments, Token,
+    TraitItemMethod,
+};
+
+/// A single option, written as `name`, `name = value` or `name(nested, options)`.
+pub struct OptionItem {
+    pub path: Path,
+    pub value: OptionValue,
+}
+
+// Not every variant's contents are read until an option which accepts them exists.
+#[allow(dead_code)]
+pub enum OptionValue {
+    /// The option was written on its own, E.G. `no_doc`.
+    Flag,
+    /// The option was assigned a value, E.G. `name = "value"`.
+    Assign(Token![=], AssignedValue),
+    /// The option contains a list of nested options, E.G. `name(a, b = "c")`.
+    List(token::Paren, Punctuated<OptionItem, Token![,]>),
+    /// The option lists method signatures, E.G. `MyTrait { fn method(&self) -> u32; }`.
+    Methods(token::Brace, Vec<TraitItemMethod>),
+}
+
+pub enum AssignedValue {
+    Lit(Lit),
+    Lifetime(Lifetime),
+    Path(Path),
+}
+
+impl Parse for OptionItem {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        // Generic arguments are allowed so that generic traits can be delegated.
+        let path = input.parse()?;
+        let value = if input.peek(Token![=]) {
+            let eq = input.parse()?;
+            let lookahead = input.lookahead1();
+            let value = if lookahead.peek(Lit) {
+                AssignedValue::Lit(input.parse()?)
+            } else if lookahead.peek(Lifetime) {
+                AssignedValue::Lifetime(input.parse()?)
+            } else if lookahead.peek(Ident) || lookahead.peek(Token![::]) {
+                AssignedValue::Path(input.parse()?)
+            } else {
+                return Err(lookahead.error());
+            };
+            OptionValue::Assign(eq, value)
+        } else if input.peek(token::Paren) {
+            let content;
+            let paren = syn::parenthesized!(content in input);
+            OptionValue::List(paren, content.parse_terminated(OptionItem::parse)?)
+        } else if input.peek(token::Brace) {
+            let content;
+            let brace = syn::braced!(content in input);
+            let mut methods = Vec::new();
+            while !content.is_empty() {
+                methods.push(content.parse()?);
+            }
+            OptionValue::Methods(brace, methods)
+        } else {
+            OptionValue::Flag
+        };
+        Ok(Self { path, value })
+    }
+}
+
+impl OptionItem {
+    /// Returns the name of this option if it is a single identifier, which is the case for every
+    /// option except for the elements of lists which are allowed to contain paths.
+    pub fn name(&self) -> Result<&Ident, Error> {
+        self.path
+            .get_ident()
+            .ok_or_else(|| Error::new_spanned(&self.path, "Expected an option name, not a path."))
+    }
+
+    /// Returns an error if this option was given a value, since it can only be used on its own.
+    pub fn expect_flag(&self) -> Result<(), Error> {
+        match &self.value {
+            OptionValue::Flag => Ok(()),
+            OptionValue::Assign(eq, value) => Err(Error::new_spanned(
+                quote! { #eq #value },
+                format!("`{}` does not take a value.", self.path_string()),
+            )),
+            OptionValue::List(paren, _) => Err(Error::new(
+                paren.span,
+                format!("`{}` does not take a list of options.", self.path_string()),
+            )),
+            OptionValue::Methods(brace, _) => Err(Error::new(
+                brace.span,
+                format!("`{}` does not take a list of methods.", self.path_string()),
+            )),
+        }
+    }
+
+    /// Returns the lifetime assigned to this option, E.G. `'x` in `lifetime = 'x`.
+    pub fn expect_lifetime(&self) -> Result<&Lifetime, Error> {
+        match &self.value {
+            OptionValue::Assign(_, AssignedValue::Lifetime(lifetime)) => Ok(lifetime),
+            OptionValue::Assign(_, value) => Err(Error::new_spanned(
+                value,
+                format!("`{}` expects a lifetime, E.G. `'x`.", self.path_string()),
+            )),
+            _ => Err(Error::new_spanned(
+                &self.path,
+                format!(
+                    "`{}` expects a lifetime, E.G. `{} = 'x`.",
+                    self.path_string(),
+                    self.path_string()
+                ),
+            )),
+        }
+    }
+
+    /// Returns the path assigned to this option, E.G. `cleanup` in `on_drop = cleanup`.
+    pub fn expect_path(&self) -> Result<&Path, Error> {
+        match &self.value {
+            OptionValue::Assign(_, AssignedValue::Path(path)) => Ok(path),
+            OptionValue::Assign(_, value) => Err(Error::new_spanned(
+                value,
+                format!(
+                    "`{}` expects the path of a function, E.G. `cleanup`.",
+                    self.path_string()
+                ),
+            )),
+            _ => Err(Error::new_spanned(
+                &self.path,
+                format!(
+                    "`{}` expects the path of a function, E.G. `{} = cleanup`.",
+                    self.path_string(),
+                    self.path_string()
+                ),
+            )),
+        }
+    }
+
+    fn path_string(&self) -> String {
+        let path = &self.path;
+        quote!(#path).to_string().replace(' ', "")
+    }
+}
+
+impl ToTokens for AssignedValue {
+    fn to_tokens(&self, tokens: &mut TokenStream2) {
+        match self {
+            Self::Lit(lit) => lit.to_tokens(tokens),
+            Self::Lifetime(lifetime) => lifetime.to_tokens(tokens),
+            Self::Path(path) => path.to_tokens(tokens),
+        }
+    }
+}
+
+/// Parses a comma-separated list of options, as found inside `#[self_referencing(...)]` or
+/// `#[ouroboros(...)]`.
+pub fn parse_option_list(input: ParseStream) -> syn::Result<Punctuated<OptionItem, Token![,]>> {
+    input.parse_terminated(OptionItem::parse)
+}
+
+/// Options which modify the behavior of the entire struct, taken from
+/// `#[self_referencing(...)]`.
+#[derive(Default)]
+pub struct Options {
+    /// Where `chain_hack` was specified, if it was. The option no longer does anything, but its
+    /// location is kept so that a deprecation warning can point at it.
+    pub chain_hack: Option<Span>,
+    pub do_no_doc: bool,
+    /// Generates a wrapper which stores the struct in a `Mutex` and provides `_locked` versions
+    /// of the accessors.
+    pub sync: bool,
+    /// The name used for the internal lifetime in place of `'this`.
+    pub lifetime: Option<Lifetime>,
+    /// A function which is given mutable access to the fields right before they are dropped.
+    pub on_drop: Option<Path>,
+}
+
+impl Options {
+    /// Parses every option, recording problems in `errors` so that they can be reported together
+    /// with any found in the struct itself. Invalid options are left at their defaults.
+    pub fn from_items(items: Punctuated<OptionItem, Token![,]>, errors: &mut Errors) -> Self {
+        let mut options = Self::default();
+        let mut seen = Vec::<Ident>::new();
+        for item in items {
+            errors.record(options.add_item(&item, &mut seen));
+        }
+        options
+    }
+
+    fn add_item(&mut self, item: &OptionItem, seen: &mut Vec<Ident>) -> Result<(), Error> {
+        let name = item.name()?;
+        if seen.iter().any(|other| other == name) {
+            return Err(Error::new_spanned(
+                name,
+                format!("Option `{}` was specified more than once.", name),
+            ));
+        }
+        seen.push(name.clone());
+        match &name.to_string()[..] {
+            "chain_hack" => {
+                item.expect_flag()?;
+                self.chain_hack = Some(name.span());
+            }
+            "no_doc" => {
+                item.expect_flag()?;
+                self.do_no_doc = true;
+            }
+            "sync" => {
+                item.expect_flag()?;
+                self.sync = true;
+            }
+            "lifetime" => {
+                let lifetime = item.expect_lifetime()?;
+                if lifetime.ident == "static" {
+                    return Err(Error::new_spanned(
+                        lifetime,
+                        "`'static` cannot be used as the internal lifetime.",
+                    ));
+                }
+                self.lifetime = Some(lifetime.clone());
+            }
+            "on_drop" => {
+                self.on_drop = Some(item.expect_path()?.clone());
+            }
+            _ => {
+                return Err(Error::new_spanned(
+                    name,
+                    format!(
+                        concat!(
+                            "Unknown option `{}`, expected `chain_hack`, `lifetime`, ",
+                            "`no_doc`, `on_drop` or `sync`."
+                        ),
+                        name
+                    ),
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A trait which can be implemented for the struct by forwarding to a field. The macro cannot see
+/// the definitions of traits, so other than the standard library traits it knows about, the methods
+/// to forward have to be listed when delegating a trait.
+#[derive(Clone)]
+pub enum DelegatedTrait {
+    Iterator,
+    DoubleEndedIterator,
+    ExactSizeIterator,
+    Read,
+    BufRead,
+    Write,
+    Display,
+    Debug,
+    Future,
+    Stream,
+    /// Any other trait, E.G. `MyTrait { fn method(&self) -> u32; }`.
+    Listed {
+        path: Path,
+        methods: Vec<TraitItemMethod>,
+    },
+}
+
+impl DelegatedTrait {
+    /// Parses an element of `delegate(...)`, which is either a path such as `Iterator`,
+    /// `io::Read` or `std::fmt::Display`, or the path of any trait followed by the methods to
+    /// forward.
+    fn from_item(item: &OptionItem) -> Result<Self, Error> {
+        if let OptionValue::Methods(_, methods) = &item.value {
+            return Self::from_methods(&item.path, methods);
+        }
+        item.expect_flag()?;
+        Self::from_path(&item.path)
+    }
+
+    fn from_methods(path: &Path, methods: &[TraitItemMethod]) -> Result<Self, Error> {
+        let mut errors = Errors::default();
+        for method in methods {
+            if let Some(default) = &method.default {
+                errors.push(Error::new_spanned(
+                    default,
+                    "Delegated methods are generated by the macro and cannot have a body.",
+                ));
+            }
+            match method.sig.inputs.first() {
+                Some(FnArg::Receiver(receiver)) if receiver.reference.is_some() => (),
+                _ => errors.push(Error::new_spanned(
+                    &method.sig,
+                    concat!(
+                        "Delegated methods must take `&self` or `&mut self`, since the field ",
+                        "can only be accessed through a reference."
+                    ),
+                )),
+            }
+        }
+        errors.finish()?;
+        // The path is used to call the trait's methods, so generic arguments need a turbofish.
+        let mut path = path.clone();
+        for segment in &mut path.segments {
+            if let PathArguments::AngleBracketed(args) = &mut segment.arguments {
+                args.colon2_token = Some(Default::default());
+            }
+        }
+        Ok(Self::Listed {
+            path,
+            methods: methods.to_vec(),
+        })
+    }
+
+    pub fn from_path(path: &Path) -> Result<Self, Error> {
+        let names: Vec<_> = path
+            .segments
+            .iter()
+            .map(|segment| segment.ident.to_string())
+            .collect();
+        let names = match &names[..] {
+            [krate, rest @ ..] if !rest.is_empty() && (krate == "std" || krate == "core") => rest,
+            names => names,
+        };
+        let (module, name) = match names {
+            [name] => (None, &name[..]),
+            [module, name] => (Some(&module[..]), &name[..]),
+            _ => (Some(""), ""),
+        };
+        match (module, name) {
+            (None | Some("iter"), "Iterator") => Ok(Self::Iterator),
+            (None | Some("iter"), "DoubleEndedIterator") => Ok(Self::DoubleEndedIterator),
+            (None | Some("iter"), "ExactSizeIterator") => Ok(Self::ExactSizeIterator),
+            (None | Some("io"), "Read") => Ok(Self::Read),
+            (None | Some("io"), "BufRead") => Ok(Self::BufRead),
+            (None | Some("io"), "Write") => Ok(Self::Write),
+            (None | Some("fmt"), "Display") => Ok(Self::Display),
+            (None | Some("fmt"), "Debug") => Ok(Self::Debug),
+            (None | Some("future"), "Future") => Ok(Self::Future),
+            (None | Some("stream") | Some("futures") | Some("futures_core"), "Stream") => {
+                Ok(Self::Stream)
+            }
+            _ => Err(Error::new_spanned(
+                path,
+                concat!(
+                    "Cannot delegate this trait without knowing its methods, either use one of ",
+                    "`Iterator`, `DoubleEndedIterator`, `ExactSizeIterator`, `std::io::Read`, ",
+                    "`std::io::BufRead`, `std::io::Write`, `std::fmt::Display`, ",
+                    "`std::fmt::Debug`, `std::future::Future` or `futures::Stream`, or list the ",
+                    "methods to forward, E.G. `MyTrait { fn method(&self) -> u32; }`."
+                ),
+            )),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Self::Iterator => "Iterator".to_owned(),
+            Self::DoubleEndedIterator => "DoubleEndedIterator".to_owned(),
+            Self::ExactSizeIterator => "ExactSizeIterator".to_owned(),
+            Self::Read => "Read".to_owned(),
+            Self::BufRead => "BufRead".to_owned(),
+            Self::Write => "Write".to_owned(),
+            Self::Display => "Display".to_owned(),
+            Self::Debug => "Debug".to_owned(),
+            Self::Future => "Future".to_owned(),
+            Self::Stream => "Stream".to_owned(),
+            Self::Listed { path, .. } => quote!(#path).to_string().replace(' ', ""),
+        }
+    }
+
+    /// Returns true if implementing the trait requires mutable access to the field.
+    pub fn needs_mut(&self) -> bool {
+        match self {
+            Self::Display | Self::Debug => false,
+            Self::Listed { methods, .. } => methods.iter().any(|method| {
+                matches!(
+                    method.sig.inputs.first(),
+                    Some(FnArg::Receiver(receiver)) if receiver.mutability.is_some()
+                )
+            }),
+            _ => true,
+        }
+    }
+
+    /// Returns true if the trait's methods take `self: Pin<&mut Self>`, requiring the field to be
+    /// structurally pinned.
+    pub fn needs_pin(&self) -> bool {
+        matches!(self, Self::Future | Self::Stream)
+    }
+}
+
+/// Options which modify the behavior of a single field, taken from `#[ouroboros(...)]`
+/// attributes placed on that field.
+#[derive(Default)]
+pub struct FieldOptions {
+    /// Traits which should be implemented by forwarding to this field, along with where they were
+    /// specified. `iterator` is shorthand for `delegate(Iterator)`.
+    pub delegate: Vec<(DelegatedTrait, Span)>,
+    /// Where `pin` was specified, if it was. Pinning the struct pins this field, allowing
+    /// `with_FIELD_pin_mut` to be generated.
+    pub pin: Option<Span>,
+}
+
+impl FieldOptions {
+    /// Adds the options from an `#[ouroboros(...)]` attribute, recording any problems in `errors`
+    /// and carrying on with the remaining options.
+    pub fn add_attr(&mut self, attr: &Attribute, errors: &mut Errors) {
+        let items = match attr.parse_args_with(parse_option_list) {
+            Ok(items) => items,
+            Err(err) => return errors.push(err),
+        };
+        for item in items {
+            let result = self.add_item(&item, errors);
+            errors.record(result);
+        }
+    }
+
+    fn add_item(&mut self, item: &OptionItem, errors: &mut Errors) -> Result<(), Error> {
+        let name = item.name()?;
+        match &name.to_string()[..] {
+            "iterator" => {
+                item.expect_flag()?;
+                self.add_delegate(DelegatedTrait::Iterator, name.span())?;
+            }
+            "future" => {
+                item.expect_flag()?;
+                self.add_delegate(DelegatedTrait::Future, name.span())?;
+            }
+            "stream" => {
+                item.expect_flag()?;
+                self.add_delegate(DelegatedTrait::Stream, name.span())?;
+            }
+            "pin" => {
+                item.expect_flag()?;
+                if self.pin.is_some() {
+                    return Err(Error::new_spanned(
+                        name,
+                        "Option `pin` was specified more than once.",
+                    ));
+                }
+                self.pin = Some(name.span());
+            }
+            "delegate" => {
+                let traits = if let OptionValue::List(_, traits) = &item.value {
+                    traits
+                } else {
+                    return Err(Error::new_spanned(
+                        name,
+                        "Expected a list of traits, E.G. `delegate(Iterator)`.",
+                    ));
+                };
+                for to_delegate in traits {
+                    let path = &to_delegate.path;
+                    errors.record(
+                        DelegatedTrait::from_item(to_delegate)
+                            .and_then(|to_delegate| self.add_delegate(to_delegate, path.span())),
+                    );
+                }
+            }
+            _ => {
+                return Err(Error::new_spanned(
+                    name,
+                    format!(
+                        concat!(
+                            "Unknown field option `{}`, expected `iterator`, `future`, ",
+                            "`stream`, `pin` or `delegate`."
+                        ),
+                        name
+                    ),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// If the field is structurally pinned, returns where that was requested.
+    pub fn pin_span(&self) -> Option<Span> {
+        self.pin.or_else(|| {
+            self.delegate
+                .iter()
+                .find(|(to_delegate, _)| to_delegate.needs_pin())
+                .map(|(_, span)| *span)
+        })
+    }
+
+    fn add_delegate(&mut self, to_delegate: DelegatedTrait, span: Span) -> Result<(), Error> {
+        let name = to_delegate.name();
+        if self.delegate.iter().any(|(other, _)| other.name() == name) {
+            return Err(Error::new(
+                span,
+                "This trait is already delegated to this field.",
+            ));
+        }
+        self.delegate.push((to_delegate, span));
+        Ok(())
+    }
+}
+
+/// A single entry in a `#[borrows(...)]` attribute, E.G. `a`, `mut b`, `&c` or `&mut d`.
+pub struct BorrowItem {
+    /// Present if the field itself should be borrowed rather than the data it derefs to.
+    pub container: Option<Token![&]>,
+    pub mutability: Option<Token![mut]>,
+    pub name: Ident,
+}
+
+impl Parse for BorrowItem {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let container: Option<Token![&]> = input.parse()?;
+        let mutability: Option<Token![mut]> = input.parse()?;
+        if input.peek(Token![mut]) {
+            return Err(input.error("Unexpected double 'mut'"));
+        }
+        let name = input.parse()?;
+        Ok(Self {
+            container,
+            mutability,
+            name,
+        })
+    }
+}
+
+/// Parses the contents of a `#[borrows(...)]` attribute.
+pub fn parse_borrows_attr(attr: &Attribute) -> Result<Vec<BorrowItem>, Error> {
+    if attr.tokens.is_empty() {
+        return Err(Error::new_spanned(
+            attr,
+            "Invalid syntax for borrows() macro, expected a list of fields.",
+        ));
+    }
+    let items = attr.parse_args_with(Punctuated::<BorrowItem, Token![,]>::parse_terminated)?;
+    Ok(items.into_iter().collect())
+}
+
+/// Returns true if the attribute is a single identifier with the given name, E.G. `#[borrows]`.
+pub fn attr_is(attr: &Attribute, name: &str) -> bool {
+    attr.path.leading_colon.is_none()
+        && attr.path.segments.len() == 1
+        && attr.path.segments[0].ident == name
+}